@@ -0,0 +1,29 @@
+#![no_main]
+
+use kv::{Key, Raw};
+use libfuzzer_sys::fuzz_target;
+use moodle_session_ext::model::{TokenId, UpdateQueueKey, WebhookDeliveryKey};
+
+// Keys read from the database must either be rejected or decode to something that encodes back to the same bytes
+fuzz_target!(|data: &[u8]| {
+    let raw = Raw::from(data);
+
+    if let Ok(id) = TokenId::from_raw_key(&raw) {
+        assert_eq!(id.as_ref(), data);
+        assert_eq!(TokenId::from(u64::from(id)), id);
+    }
+
+    if let Ok(key) = UpdateQueueKey::from_raw_key(&raw) {
+        assert_eq!(key.as_ref(), data);
+        let reencoded = UpdateQueueKey::try_from((key.next_refresh(), key.token_id()))
+            .expect("a decoded key should encode again");
+        assert_eq!(reencoded.as_ref(), data);
+    }
+
+    if let Ok(key) = WebhookDeliveryKey::from_raw_key(&raw) {
+        assert_eq!(key.as_ref(), data);
+        let reencoded = WebhookDeliveryKey::try_from((key.next_attempt(), key.delivery_id()))
+            .expect("a decoded key should encode again");
+        assert_eq!(reencoded.as_ref(), data);
+    }
+});
@@ -0,0 +1,1006 @@
+use crate::config::Secret;
+use crate::cookies::CookieJar;
+use crate::db::UserLimitReached;
+use crate::events::{Event, Payload, RevokeReason};
+use crate::model::{unix_millis, Credential, CredentialKind, Email, RefreshResult, Token};
+use crate::moodle::{is_timed_out, Busy, Moodle, RateLimited, SessionProbeResult};
+use crate::server::Data;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::{AUTHORIZATION, CACHE_CONTROL};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, ResponseError};
+use futures_util::{stream, StreamExt};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime};
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::interval;
+use tracing::{error, info, warn};
+use utoipa::{OpenApi, ToSchema};
+
+/// How often to send a comment over an idle event stream, so that proxies don't close it
+const EVENT_STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Machine-readable error codes. Clients rely on them, so existing codes must not change
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request body is malformed
+    InvalidRequest,
+    /// There is no such route
+    NotFound,
+    /// Moodle does not accept the session
+    SessionInvalid,
+    /// The session is not being extended
+    SessionNotFound,
+    /// The session belongs to a user that never had a session extended
+    UserNotFound,
+    /// The session belongs to a moodle instance this server doesn't work with
+    UnknownInstance,
+    BatchTooLarge,
+    /// The user already has as many sessions extended as allowed
+    UserLimitReached,
+    /// Moodle asked us to slow down, try again later
+    RateLimited,
+    /// Moodle could not be reached or responded with something unexpected
+    MoodleUnavailable,
+    /// Moodle did not respond in time, try again later
+    MoodleTimeout,
+    /// The operator token is missing or wrong
+    Unauthorized,
+    Internal,
+}
+
+impl ErrorCode {
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::UnknownInstance => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound | ErrorCode::SessionNotFound | ErrorCode::UserNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::SessionInvalid | ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UserLimitReached => StatusCode::CONFLICT,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::MoodleUnavailable => StatusCode::BAD_GATEWAY,
+            ErrorCode::MoodleTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    /// Human-readable description, not meant to be parsed
+    pub message: String,
+}
+
+/// Body of every error response of the API
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorEnvelope {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// For errors of requests to moodle
+    fn moodle(e: anyhow::Error) -> Self {
+        if e.chain().any(|e| e.is::<RateLimited>() || e.is::<Busy>()) {
+            return Self::new(ErrorCode::RateLimited, e.to_string());
+        }
+        if is_timed_out(&e) {
+            warn!("Moodle request timed out: {:#}", e);
+            return Self::new(ErrorCode::MoodleTimeout, format!("{:#}", e));
+        }
+
+        warn!("Moodle request failed: {:?}", e);
+        Self::new(ErrorCode::MoodleUnavailable, format!("{:#}", e))
+    }
+
+    fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code,
+            message: self.message.clone(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(limit) = e.downcast_ref::<UserLimitReached>() {
+            return Self::new(ErrorCode::UserLimitReached, limit.to_string());
+        }
+
+        error!("Encountered an error: {:?}", e);
+        Self::new(ErrorCode::Internal, "Internal server error")
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorEnvelope { error: self.body() })
+    }
+}
+
+pub fn json_error_handler(e: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::new(ErrorCode::InvalidRequest, e.to_string()).into()
+}
+
+async fn not_found() -> HttpResponse {
+    ApiError::new(ErrorCode::NotFound, "No such route").error_response()
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SessionRequest {
+    /// The session cookie, or the web service token
+    pub moodle_session: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ExtendRequest {
+    /// The session cookie. Either it or `wstoken` must be set
+    #[serde(default)]
+    pub moodle_session: Option<String>,
+    /// A web service token (e.g. of the mobile app), for sites where session cookies can't be kept alive
+    #[serde(default)]
+    pub wstoken: Option<String>,
+    /// URL of the moodle instance the session belongs to. Only the configured instance is supported
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Stop extending the session at this time (in milliseconds since the unix epoch), which must be in the future
+    #[serde(default)]
+    pub extend_until: Option<u64>,
+    /// Stop extending the session after this many seconds
+    #[serde(default)]
+    pub extend_for_secs: Option<u64>,
+    /// Other cookies moodle needs along with the session one. Cookies of other domains are ignored
+    #[serde(default)]
+    pub cookies: Vec<RequestCookie>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RequestCookie {
+    pub name: String,
+    pub value: String,
+    /// The cookie domain, as reported by the browser. Host-only cookies of the moodle instance don't have one
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+/// Whether a cookie of `domain` is sent to `host`
+fn domain_matches(domain: &str, host: &str) -> bool {
+    let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+impl ExtendRequest {
+    /// The session cookie along with the other submitted cookies of the moodle domain
+    fn cookie_jar(&self, moodle_session: &str, moodle: &Moodle) -> CookieJar {
+        let host = moodle.base_url().host_str().unwrap_or_default();
+
+        let mut jar = CookieJar::new(moodle_session);
+        for cookie in &self.cookies {
+            if cookie.name != moodle.session_cookie()
+                && cookie
+                    .domain
+                    .as_deref()
+                    .is_none_or(|domain| domain_matches(domain, host))
+            {
+                jar.insert(&cookie.name, &cookie.value);
+            }
+        }
+
+        jar
+    }
+
+    /// When to stop extending the session, taking the server limit into account
+    fn extend_until(
+        &self,
+        now: SystemTime,
+        max_extension_period: Option<Duration>,
+    ) -> Result<Option<SystemTime>, ApiError> {
+        let out_of_range = |field| {
+            ApiError::new(
+                ErrorCode::InvalidRequest,
+                format!("`{}` is out of range", field),
+            )
+        };
+
+        let requested_until = self
+            .extend_until
+            .map(|millis| {
+                SystemTime::UNIX_EPOCH
+                    .checked_add(Duration::from_millis(millis))
+                    .ok_or_else(|| out_of_range("extend_until"))
+            })
+            .transpose()?;
+        if requested_until.is_some_and(|until| until <= now) {
+            return Err(ApiError::new(
+                ErrorCode::InvalidRequest,
+                "`extend_until` is in the past",
+            ));
+        }
+        let requested_for = self
+            .extend_for_secs
+            .map(|secs| {
+                now.checked_add(Duration::from_secs(secs))
+                    .ok_or_else(|| out_of_range("extend_for_secs"))
+            })
+            .transpose()?;
+        // a period too long to represent doesn't limit anything
+        let limit = max_extension_period.and_then(|period| now.checked_add(period));
+
+        Ok([requested_until, requested_for, limit]
+            .into_iter()
+            .flatten()
+            .min())
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ExtendResponse {
+    /// Owner of the session
+    pub email: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct BatchExtendRequest {
+    pub sessions: Vec<ExtendRequest>,
+}
+
+/// Either `email` or `error` is set
+#[derive(Serialize, ToSchema)]
+pub struct BatchExtendResult {
+    pub email: Option<String>,
+    pub error: Option<ErrorBody>,
+}
+
+/// Results are in the same order as the submitted sessions
+#[derive(Serialize, ToSchema)]
+pub struct BatchExtendResponse {
+    pub results: Vec<BatchExtendResult>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RefreshOutcomeResponse {
+    pub at: u64,
+    pub extended: bool,
+    pub time_left_secs: Option<u64>,
+    pub error: Option<String>,
+    /// Moodle didn't respond in time. Such refreshes are retried shortly
+    pub timed_out: bool,
+}
+
+/// All times are in milliseconds since the unix epoch
+#[derive(Serialize, ToSchema)]
+pub struct TokenStatus {
+    pub email: String,
+    pub credential: CredentialKind,
+    pub added: u64,
+    pub deadline: u64,
+    pub next_refresh: u64,
+    pub last_extended: Option<u64>,
+    pub extension_count: u64,
+    pub last_time_remaining_secs: Option<u64>,
+    pub last_error: Option<String>,
+    pub recent_outcomes: Vec<RefreshOutcomeResponse>,
+    pub extend_until: Option<u64>,
+    /// Number of refreshes that started after the deadline, when the session may have expired already
+    pub missed_deadlines: u32,
+    /// When moodle last issued a new session cookie. The new value itself is only sent over `/v1/session-events`
+    pub rotated_at: Option<u64>,
+}
+
+impl From<Token> for TokenStatus {
+    fn from(token: Token) -> Self {
+        Self {
+            email: token.owner.0,
+            credential: token.credential,
+            added: unix_millis(token.added),
+            deadline: unix_millis(token.deadline),
+            next_refresh: unix_millis(token.next_refresh),
+            last_extended: token.last_extended.map(unix_millis),
+            extension_count: token.extension_count,
+            last_time_remaining_secs: token.last_time_remaining.map(|d| d.as_secs()),
+            last_error: token.last_error,
+            recent_outcomes: token
+                .recent_outcomes
+                .into_iter()
+                .map(|outcome| match outcome.result {
+                    RefreshResult::Extended { time_left } => RefreshOutcomeResponse {
+                        at: unix_millis(outcome.at),
+                        extended: true,
+                        time_left_secs: Some(time_left.as_secs()),
+                        error: None,
+                        timed_out: false,
+                    },
+                    RefreshResult::Failed { error } => RefreshOutcomeResponse {
+                        at: unix_millis(outcome.at),
+                        extended: false,
+                        time_left_secs: None,
+                        error: Some(error),
+                        timed_out: false,
+                    },
+                    RefreshResult::TimedOut { error } => RefreshOutcomeResponse {
+                        at: unix_millis(outcome.at),
+                        extended: false,
+                        time_left_secs: None,
+                        error: Some(error),
+                        timed_out: true,
+                    },
+                })
+                .collect(),
+            extend_until: token.extend_until.map(unix_millis),
+            missed_deadlines: token.missed_deadlines,
+            rotated_at: token.rotated_at.map(unix_millis),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NotificationSettingsRequest {
+    pub moodle_session: String,
+    pub enabled: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NotificationSettingsResponse {
+    pub enabled: bool,
+}
+
+/// Checks the session with moodle and finds out who it belongs to
+async fn authenticate(data: &Data, moodle_session: &str) -> Result<Email, ApiError> {
+    match data
+        .moodle
+        .with_timeout(
+            data.moodle
+                .check_session(&mut CookieJar::new(moodle_session)),
+        )
+        .await
+        .map_err(ApiError::moodle)?
+    {
+        SessionProbeResult::Invalid => Err(ApiError::new(
+            ErrorCode::SessionInvalid,
+            "Moodle does not accept the session",
+        )),
+        SessionProbeResult::Valid { email, .. } => Ok(email),
+    }
+}
+
+/// Checks the session with moodle and, if it's valid, starts extending it. Returns the email of the session owner,
+/// or `None` if the session is invalid
+pub async fn extend(data: &Data, request: &ExtendRequest) -> Result<Option<String>, ApiError> {
+    if let Some(instance) = &request.instance {
+        let instance = Url::parse(instance).map_err(|e| {
+            ApiError::new(
+                ErrorCode::InvalidRequest,
+                format!("Invalid instance URL: {}", e),
+            )
+        })?;
+        if instance.origin() != data.moodle.base_url().origin() {
+            return Err(ApiError::new(
+                ErrorCode::UnknownInstance,
+                format!("Unknown moodle instance {}", instance),
+            ));
+        }
+    }
+
+    let extend_until =
+        request.extend_until(data.db.clock().now(), data.config.max_extension_period)?;
+
+    let (probe, mut credential) = match (&request.moodle_session, &request.wstoken) {
+        (Some(moodle_session), None) => {
+            let mut cookies = request.cookie_jar(moodle_session, &data.moodle);
+            let probe = data
+                .moodle
+                .with_timeout(data.moodle.check_session(&mut cookies))
+                .await
+                .map_err(ApiError::moodle)?;
+            let credential = Credential::BrowserSession {
+                moodle_session: moodle_session.clone(),
+                // known once the session is checked
+                csrf_session: String::new(),
+                cookies,
+            };
+            (probe, credential)
+        }
+        (None, Some(wstoken)) => {
+            let probe = data
+                .moodle
+                .with_timeout(data.moodle.check_web_service_token(wstoken))
+                .await
+                .map_err(ApiError::moodle)?;
+            (probe, Credential::WebServiceToken(wstoken.clone()))
+        }
+        _ => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidRequest,
+                "Exactly one of `moodle_session` and `wstoken` must be set",
+            ))
+        }
+    };
+
+    let email = match probe {
+        SessionProbeResult::Invalid => {
+            info!("Provided credential is invalid");
+            return Ok(None);
+        }
+        SessionProbeResult::Valid {
+            email,
+            csrf_session: sesskey,
+        } => {
+            if let Credential::BrowserSession {
+                moodle_session,
+                csrf_session,
+                cookies,
+            } = &mut credential
+            {
+                *csrf_session = sesskey;
+                if let Some(rotated) = cookies.session().filter(|s| s != moodle_session) {
+                    info!("Moodle rotated the session cookie while it was checked");
+                    *moodle_session = rotated.to_string();
+                }
+            }
+            email
+        }
+    };
+
+    info!(
+        "Provided credential is valid, adding to database (extending until {:?})",
+        extend_until
+    );
+    let added = data
+        .db
+        .add_token(&email, &credential, extend_until, &data.config.user_limit)?;
+    for (token_id, token) in &added.evicted {
+        data.events.emit(Event::evicted(*token_id, token));
+    }
+    if added.new {
+        data.events
+            .emit(Event::registered(added.token_id, &added.token));
+    }
+
+    Ok(Some(email.0))
+}
+
+fn session_invalid() -> ApiError {
+    ApiError::new(
+        ErrorCode::SessionInvalid,
+        "Moodle does not accept the session",
+    )
+}
+
+/// Starts extending the session
+#[utoipa::path(
+    post,
+    path = "/v1/extend-session",
+    request_body = ExtendRequest,
+    responses(
+        (status = 200, body = ExtendResponse),
+        (status = 400, body = ErrorEnvelope, description = "`invalid_request` or `unknown_instance`"),
+        (status = 401, body = ErrorEnvelope, description = "`session_invalid`"),
+        (status = 409, body = ErrorEnvelope, description = "`user_limit_reached`"),
+        (status = 429, body = ErrorEnvelope, description = "`rate_limited`"),
+        (status = 502, body = ErrorEnvelope, description = "`moodle_unavailable`"),
+    )
+)]
+#[post("/extend-session")]
+async fn extend_session(
+    data: web::Data<Data>,
+    request: web::Json<ExtendRequest>,
+) -> Result<web::Json<ExtendResponse>, ApiError> {
+    let email = extend(&data, &request).await?.ok_or_else(session_invalid)?;
+
+    Ok(web::Json(ExtendResponse { email }))
+}
+
+/// Extends several sessions at once. The sessions are checked concurrently (but still subject to the rate limit),
+/// and a failure of one of them doesn't affect the others
+#[utoipa::path(
+    post,
+    path = "/v1/extend-sessions",
+    request_body = BatchExtendRequest,
+    responses(
+        (status = 200, body = BatchExtendResponse),
+        (status = 413, body = ErrorEnvelope, description = "`batch_too_large`"),
+    )
+)]
+#[post("/extend-sessions")]
+async fn extend_sessions(
+    data: web::Data<Data>,
+    request: web::Json<BatchExtendRequest>,
+) -> Result<web::Json<BatchExtendResponse>, ApiError> {
+    let batch = &data.config.batch;
+    if request.sessions.len() > batch.max_size {
+        return Err(ApiError::new(
+            ErrorCode::BatchTooLarge,
+            format!(
+                "At most {} sessions can be submitted at once",
+                batch.max_size
+            ),
+        ));
+    }
+
+    let results = stream::iter(&request.sessions)
+        .map(|request| extend(&data, request))
+        .buffered(batch.concurrency)
+        .map(
+            |result| match result.and_then(|email| email.ok_or_else(session_invalid)) {
+                Ok(email) => BatchExtendResult {
+                    email: Some(email),
+                    error: None,
+                },
+                Err(e) => BatchExtendResult {
+                    email: None,
+                    error: Some(e.body()),
+                },
+            },
+        )
+        .collect::<Vec<_>>()
+        .await;
+
+    Ok(web::Json(BatchExtendResponse { results }))
+}
+
+/// Reports how the extension of the given session is going. Knowing the session is enough to see its status
+#[utoipa::path(
+    post,
+    path = "/v1/session-status",
+    request_body = SessionRequest,
+    responses(
+        (status = 200, body = TokenStatus),
+        (status = 404, body = ErrorEnvelope, description = "`session_not_found`"),
+    )
+)]
+#[post("/session-status")]
+async fn session_status(
+    data: web::Data<Data>,
+    request: web::Json<SessionRequest>,
+) -> Result<web::Json<TokenStatus>, ApiError> {
+    match data.db.find_token_by_session(&request.moodle_session)? {
+        Some((_, token)) => Ok(web::Json(token.into())),
+        None => Err(ApiError::new(
+            ErrorCode::SessionNotFound,
+            "The session is not being extended",
+        )),
+    }
+}
+
+/// Event streams only carry the events of the user's own sessions, so unlike webhooks they get the new value of a
+/// rotated session cookie
+fn event_stream_message(payload: &Payload) -> Option<web::Bytes> {
+    let json = serde_json::to_value(payload).and_then(|mut json| {
+        if let Event::TokenSessionRotated { moodle_session, .. } = &payload.event {
+            json["moodle_session"] = moodle_session.expose().into();
+        }
+        serde_json::to_string(&json)
+    });
+    match json {
+        Ok(json) => Some(web::Bytes::from(format!("data: {}\n\n", json))),
+        Err(e) => {
+            error!("Failed to serialize event {:?}: {:?}", payload, e);
+            None
+        }
+    }
+}
+
+/// Streams the events of all sessions of the user as server-sent events. The session is checked with moodle to find
+/// out who the user is. As `EventSource` can't send a body, the stream has to be read with `fetch`
+#[utoipa::path(
+    post,
+    path = "/v1/session-events",
+    request_body = SessionRequest,
+    responses(
+        (status = 200, content_type = "text/event-stream", description = "Stream of session events, each `data` is the same JSON as a webhook body, `token_session_rotated` also has the new `moodle_session`"),
+        (status = 401, body = ErrorEnvelope, description = "`session_invalid`"),
+        (status = 502, body = ErrorEnvelope, description = "`moodle_unavailable`"),
+    )
+)]
+#[post("/session-events")]
+async fn session_events(
+    data: web::Data<Data>,
+    request: web::Json<SessionRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let email = authenticate(&data, &request.moodle_session).await?;
+    info!("{} subscribed to session events", email.0);
+
+    let state = (
+        data.events.subscribe(),
+        interval(EVENT_STREAM_KEEPALIVE),
+        data.stopping.clone(),
+        email,
+    );
+    let events = stream::unfold(
+        state,
+        |(mut receiver, mut keepalive, mut stopping, email)| async move {
+            let message = loop {
+                if *stopping.borrow() {
+                    // the client reconnects to the restarted server
+                    return None;
+                }
+                select! {
+                    _ = keepalive.tick() => break web::Bytes::from_static(b": keepalive\n\n"),
+                    changed = stopping.changed() => if changed.is_err() {
+                        return None;
+                    },
+                    payload = receiver.recv() => match payload {
+                        Ok(payload) if payload.event.email() == email.0 => {
+                            if let Some(message) = event_stream_message(&payload) {
+                                break message;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(missed)) => {
+                            // let the client know that it should re-fetch the status
+                            warn!("Event subscriber of {} lagged behind by {} events", email.0, missed);
+                            break web::Bytes::from(format!("event: lagged\ndata: {}\n\n", missed));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                }
+            };
+
+            Some((
+                Ok::<_, actix_web::Error>(message),
+                (receiver, keepalive, stopping, email),
+            ))
+        },
+    );
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .content_type("text/event-stream")
+        .streaming(events))
+}
+
+/// Stops extending the given session. Knowing the session is enough to revoke it
+#[utoipa::path(
+    post,
+    path = "/v1/revoke-session",
+    request_body = SessionRequest,
+    responses(
+        (status = 204, description = "The session is not extended anymore"),
+        (status = 404, body = ErrorEnvelope, description = "`session_not_found`"),
+    )
+)]
+#[post("/revoke-session")]
+async fn revoke_session(
+    data: web::Data<Data>,
+    request: web::Json<SessionRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let token_id = match data.db.find_token_by_session(&request.moodle_session)? {
+        Some((token_id, _)) => token_id,
+        None => {
+            return Err(ApiError::new(
+                ErrorCode::SessionNotFound,
+                "The session is not being extended",
+            ))
+        }
+    };
+
+    info!("Revoking {:?} on user request", token_id);
+    if let Some(token) = data.db.remove_token(token_id)? {
+        data.events
+            .emit(Event::revoked(token_id, &token, RevokeReason::Requested));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lets the user opt out of (or back into) emails about their sessions. The session is checked with moodle,
+/// so that it also works when none of the user's sessions are alive anymore
+#[utoipa::path(
+    post,
+    path = "/v1/notification-settings",
+    request_body = NotificationSettingsRequest,
+    responses(
+        (status = 200, body = NotificationSettingsResponse),
+        (status = 401, body = ErrorEnvelope, description = "`session_invalid`"),
+        (status = 404, body = ErrorEnvelope, description = "`user_not_found`"),
+        (status = 502, body = ErrorEnvelope, description = "`moodle_unavailable`"),
+    )
+)]
+#[post("/notification-settings")]
+async fn notification_settings(
+    data: web::Data<Data>,
+    request: web::Json<NotificationSettingsRequest>,
+) -> Result<web::Json<NotificationSettingsResponse>, ApiError> {
+    let email = authenticate(&data, &request.moodle_session).await?;
+
+    info!(
+        "Setting notifications for {} to {}",
+        email.0, request.enabled
+    );
+    if !data
+        .db
+        .set_notifications_opted_out(&email, !request.enabled)?
+    {
+        return Err(ApiError::new(
+            ErrorCode::UserNotFound,
+            "No session of this user was ever extended",
+        ));
+    }
+
+    Ok(web::Json(NotificationSettingsResponse {
+        enabled: request.enabled,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StatsResponse {
+    /// Number of sessions being extended
+    pub tokens: usize,
+    /// Refreshes that started after the deadline of their session, over the lifetime of the database
+    pub missed_deadlines: u64,
+}
+
+/// Accepts the request if it carries `Authorization: Bearer <operator_token>`. Without a configured token, the
+/// operator routes don't exist
+fn check_operator_token(
+    expected: Option<&Secret>,
+    authorization: Option<&str>,
+) -> Result<(), ApiError> {
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return Err(ApiError::new(ErrorCode::NotFound, "No such route")),
+    };
+
+    // comparing digests, so that the time taken doesn't depend on how much of the token is right
+    let presented = authorization.and_then(|a| a.strip_prefix("Bearer "));
+    let matches = presented.is_some_and(|presented| {
+        Sha256::digest(presented.as_bytes()) == Sha256::digest(expected.expose().as_bytes())
+    });
+    if !matches {
+        return Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "The operator token is missing or wrong",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Server-wide numbers for monitoring. Needs `Authorization: Bearer <operator_token>`
+#[utoipa::path(
+    get,
+    path = "/v1/stats",
+    responses(
+        (status = 200, body = StatsResponse),
+        (status = 401, body = ErrorEnvelope, description = "`unauthorized`"),
+        (status = 404, body = ErrorEnvelope, description = "`not_found`, if no operator token is configured"),
+    )
+)]
+#[get("/stats")]
+async fn stats(
+    data: web::Data<Data>,
+    request: HttpRequest,
+) -> Result<web::Json<StatsResponse>, ApiError> {
+    let authorization = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok());
+    check_operator_token(data.config.operator_token.as_ref(), authorization)?;
+
+    Ok(web::Json(StatsResponse {
+        tokens: data.db.get_token_count()?,
+        missed_deadlines: data.db.get_stats()?.missed_deadlines,
+    }))
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        extend_session,
+        extend_sessions,
+        session_status,
+        session_events,
+        revoke_session,
+        notification_settings,
+        stats
+    ),
+    components(schemas(
+        ErrorCode,
+        ErrorBody,
+        ErrorEnvelope,
+        CredentialKind,
+        SessionRequest,
+        ExtendRequest,
+        RequestCookie,
+        ExtendResponse,
+        BatchExtendRequest,
+        BatchExtendResult,
+        BatchExtendResponse,
+        RefreshOutcomeResponse,
+        TokenStatus,
+        NotificationSettingsRequest,
+        NotificationSettingsResponse,
+        StatsResponse
+    ))
+)]
+struct ApiDoc;
+
+/// The OpenAPI description of this API
+#[get("/openapi.json")]
+async fn openapi_document() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// Registers the `/v1` routes
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/v1")
+            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .service(extend_session)
+            .service(extend_sessions)
+            .service(session_status)
+            .service(session_events)
+            .service(revoke_session)
+            .service(notification_settings)
+            .service(stats)
+            .service(openapi_document)
+            .default_service(web::to(not_found)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(extend_until: Option<u64>, extend_for_secs: Option<u64>) -> ExtendRequest {
+        ExtendRequest {
+            moodle_session: Some("s3ss10n".to_string()),
+            wstoken: None,
+            instance: None,
+            extend_until,
+            extend_for_secs,
+            cookies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn extends_until_the_earliest_limit() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let hour = Duration::from_secs(60 * 60);
+        let in_two_hours = 1_700_000_000_000 + 2 * 60 * 60 * 1000;
+
+        assert_eq!(request(None, None).extend_until(now, None).unwrap(), None);
+        assert_eq!(
+            request(Some(in_two_hours), Some(60 * 60))
+                .extend_until(now, None)
+                .unwrap(),
+            Some(now + hour)
+        );
+        assert_eq!(
+            request(Some(in_two_hours), None)
+                .extend_until(now, Some(3 * hour))
+                .unwrap(),
+            Some(now + 2 * hour)
+        );
+        assert_eq!(
+            request(None, Some(24 * 60 * 60))
+                .extend_until(now, Some(hour))
+                .unwrap(),
+            Some(now + hour)
+        );
+    }
+
+    #[test]
+    fn rejects_times_out_of_range() {
+        let now = SystemTime::now();
+
+        assert!(request(None, Some(u64::MAX))
+            .extend_until(now, None)
+            .is_err());
+        assert!(request(Some(u64::MAX), Some(u64::MAX))
+            .extend_until(now, Some(Duration::from_secs(60)))
+            .is_err());
+        assert_eq!(
+            request(None, Some(60))
+                .extend_until(now, Some(Duration::MAX))
+                .unwrap(),
+            Some(now + Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn rejects_times_in_the_past() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        for extend_until in [0, 1_699_999_999_000, 1_700_000_000_000] {
+            let error = request(Some(extend_until), None)
+                .extend_until(now, None)
+                .unwrap_err();
+            assert!(matches!(error.code, ErrorCode::InvalidRequest));
+        }
+        assert!(request(Some(1_700_000_000_001), None)
+            .extend_until(now, None)
+            .is_ok());
+    }
+
+    #[test]
+    fn session_status_tells_of_a_rotation_but_not_the_new_value() {
+        let added = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut token = Token::new(
+            Email("student@example.edu".to_string()),
+            "f1rst".to_string(),
+            "s3ssk3y".to_string(),
+            added,
+        );
+        token.rotate_session("r0t4t3d".to_string());
+        token.rotated_at = Some(added + Duration::from_secs(60));
+
+        let status = serde_json::to_string(&TokenStatus::from(token)).unwrap();
+        assert!(status.contains(r#""rotated_at":1700000060000"#));
+        assert!(!status.contains("r0t4t3d"));
+        assert!(!status.contains("f1rst"));
+    }
+
+    #[test]
+    fn only_event_streams_get_the_rotated_session() {
+        let payload = Payload {
+            event: Event::TokenSessionRotated {
+                token_id: 1,
+                email: "student@example.edu".to_string(),
+                moodle_session: "r0t4t3d".to_string().into(),
+            },
+            at: 0,
+        };
+
+        let message = event_stream_message(&payload).unwrap();
+        assert!(String::from_utf8_lossy(&message).contains(r#""moodle_session":"r0t4t3d""#));
+        assert!(!serde_json::to_string(&payload).unwrap().contains("r0t4t3d"));
+    }
+
+    #[test]
+    fn operator_routes_need_the_configured_token() {
+        let token: Secret = serde_yaml::from_str("0p3r4t0r").unwrap();
+        let code = |expected, authorization| {
+            check_operator_token(expected, authorization)
+                .err()
+                .map(|e| e.code)
+        };
+
+        assert_eq!(code(Some(&token), Some("Bearer 0p3r4t0r")), None);
+        assert_eq!(
+            code(Some(&token), Some("Bearer 0p3r4t0")),
+            Some(ErrorCode::Unauthorized)
+        );
+        assert_eq!(
+            code(Some(&token), Some("0p3r4t0r")),
+            Some(ErrorCode::Unauthorized)
+        );
+        assert_eq!(code(Some(&token), None), Some(ErrorCode::Unauthorized));
+        assert_eq!(
+            code(None, Some("Bearer 0p3r4t0r")),
+            Some(ErrorCode::NotFound)
+        );
+    }
+}
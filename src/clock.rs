@@ -0,0 +1,36 @@
+use std::time::SystemTime;
+
+/// Where the scheduler gets the current time from, so that it can be driven without waiting for real time to pass
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that moves together with tokio's timers, so that pausing and advancing tokio's time (e.g. in
+/// `#[tokio::test(start_paused = true)]`) moves the schedule and the timers waiting on it alike
+pub struct TokioClock {
+    start: SystemTime,
+    started: tokio::time::Instant,
+}
+
+impl TokioClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            start,
+            started: tokio::time::Instant::now(),
+        }
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> SystemTime {
+        self.start + self.started.elapsed()
+    }
+}
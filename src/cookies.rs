@@ -0,0 +1,261 @@
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use cookie::Cookie;
+use reqwest::header::HeaderValue;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use tracing::debug;
+
+/// Length of the key in `database.encryption_key`
+pub const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+
+/// The session cookie along with the other cookies sent to moodle (load balancer stickiness, `MOODLEID1_`,
+/// WAF clearance etc.). The session cookie is kept apart, as its name depends on the moodle instance
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CookieJar {
+    session: Option<String>,
+    others: BTreeMap<String, String>,
+}
+
+impl CookieJar {
+    /// A jar with only the session cookie
+    pub fn new(moodle_session: &str) -> Self {
+        Self {
+            session: Some(moodle_session.to_string()),
+            others: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.others.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    /// Number of cookies besides the session one
+    pub fn others_len(&self) -> usize {
+        self.others.len()
+    }
+
+    pub fn header_value(&self, session_cookie: &str) -> Result<HeaderValue> {
+        let header = self
+            .session
+            .iter()
+            .map(|value| (session_cookie, value))
+            .chain(self.others.iter().map(|(n, v)| (n.as_str(), v)))
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Ok(HeaderValue::from_str(&header)?)
+    }
+
+    /// Applies the `Set-Cookie` headers of a moodle response. Cookies that are cleared or expired are removed
+    pub fn merge_set_cookies<'a>(
+        &mut self,
+        session_cookie: &str,
+        headers: impl Iterator<Item = &'a HeaderValue>,
+    ) {
+        let now = SystemTime::now();
+
+        for header in headers {
+            let cookie = match header
+                .to_str()
+                .map_err(|e| e.to_string())
+                .and_then(|h| Cookie::parse(h).map_err(|e| e.to_string()))
+            {
+                Ok(c) => c,
+                Err(e) => {
+                    debug!("Ignoring a malformed Set-Cookie header: {}", e);
+                    continue;
+                }
+            };
+
+            let expired = cookie
+                .max_age()
+                .is_some_and(|age| age.is_zero() || age.is_negative())
+                || cookie
+                    .expires_datetime()
+                    .is_some_and(|expires| SystemTime::from(expires) <= now);
+            let value =
+                (!expired && !cookie.value().is_empty()).then(|| cookie.value().to_string());
+
+            if cookie.name() == session_cookie {
+                self.session = value;
+            } else {
+                match value {
+                    Some(value) => self.others.insert(cookie.name().to_string(), value),
+                    None => self.others.remove(cookie.name()),
+                };
+            }
+        }
+    }
+}
+
+/// Encrypts cookie jars and web service tokens before they are written to the database
+pub struct CookieCipher(ChaCha20Poly1305);
+
+impl CookieCipher {
+    /// Takes the hex-encoded key
+    pub fn new(key: &str) -> Result<Self> {
+        let key = hex::decode(key).context("Decoding the cookie encryption key")?;
+        if key.len() != KEY_LENGTH {
+            return Err(anyhow!(
+                "The cookie encryption key must be {} bytes long",
+                KEY_LENGTH
+            ));
+        }
+
+        Ok(Self(ChaCha20Poly1305::new(Key::from_slice(&key))))
+    }
+
+    /// Seals the cookies other than the session one. The ciphertext is bound to the session, so that it can't be
+    /// moved to another token
+    pub fn seal(&self, moodle_session: &str, jar: &CookieJar) -> Result<String> {
+        self.seal_bytes(moodle_session, &serde_json::to_vec(&jar.others)?)
+    }
+
+    pub fn open(&self, moodle_session: &str, sealed: &str) -> Result<CookieJar> {
+        let plaintext = self.open_bytes(moodle_session, sealed)?;
+
+        Ok(CookieJar {
+            session: Some(moodle_session.to_string()),
+            others: serde_json::from_slice(&plaintext)?,
+        })
+    }
+
+    /// Seals a web service token. The ciphertext is bound to the owner, so that it can't be moved to another user
+    pub fn seal_secret(&self, owner: &str, secret: &str) -> Result<String> {
+        self.seal_bytes(owner, secret.as_bytes())
+    }
+
+    pub fn open_secret(&self, owner: &str, sealed: &str) -> Result<String> {
+        Ok(String::from_utf8(self.open_bytes(owner, sealed)?)?)
+    }
+
+    fn seal_bytes(&self, aad: &str, plaintext: &[u8]) -> Result<String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(hex::encode(sealed))
+    }
+
+    fn open_bytes(&self, aad: &str, sealed: &str) -> Result<Vec<u8>> {
+        let sealed = hex::decode(sealed).context("Decoding the sealed value")?;
+        if sealed.len() < NONCE_LENGTH {
+            return Err(anyhow!("The sealed value is too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+
+        self.0
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt, was the key changed?"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Instances setting `$CFG->sessioncookie = "prod"`
+    const SESSION_COOKIE: &str = "MoodleSessionprod";
+
+    fn merge(jar: &mut CookieJar, headers: &[&str]) {
+        let headers = headers
+            .iter()
+            .map(|h| HeaderValue::from_str(h).unwrap())
+            .collect::<Vec<_>>();
+        jar.merge_set_cookies(SESSION_COOKIE, headers.iter());
+    }
+
+    #[test]
+    fn header_names_the_session_cookie_after_the_instance() {
+        let mut jar = CookieJar::new("s3ss10n");
+        jar.insert("MOODLEID1_prod", "%25ED");
+        jar.insert("lb", "node2");
+
+        assert_eq!(
+            jar.header_value(SESSION_COOKIE).unwrap(),
+            "MoodleSessionprod=s3ss10n; MOODLEID1_prod=%25ED; lb=node2"
+        );
+        assert_eq!(
+            CookieJar::default().header_value(SESSION_COOKIE).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn merges_the_session_cookie_by_its_configured_name() {
+        let mut jar = CookieJar::new("old");
+        merge(
+            &mut jar,
+            &[
+                "MoodleSessionprod=new; path=/moodle/; secure; HttpOnly",
+                // the default name is just another cookie on this instance
+                "MoodleSession=other; path=/",
+                "lb=node1; path=/",
+            ],
+        );
+
+        assert_eq!(jar.session(), Some("new"));
+        assert_eq!(
+            jar.header_value(SESSION_COOKIE).unwrap(),
+            "MoodleSessionprod=new; MoodleSession=other; lb=node1"
+        );
+    }
+
+    #[test]
+    fn removes_cleared_and_expired_cookies() {
+        let mut jar = CookieJar::new("s3ss10n");
+        jar.insert("lb", "node2");
+        jar.insert("waf", "clearance");
+        merge(
+            &mut jar,
+            &[
+                "MoodleSessionprod=deleted; expires=Thu, 01-Jan-1970 00:00:01 GMT; Max-Age=0; path=/",
+                "lb=; path=/",
+                "waf=clearance; expires=Thu, 01-Jan-1970 00:00:01 GMT",
+                "not a cookie",
+            ],
+        );
+
+        assert_eq!(jar.session(), None);
+        assert_eq!(jar.others_len(), 0);
+    }
+
+    #[test]
+    fn secrets_are_bound_to_their_owner() {
+        let cipher = CookieCipher::new(&"4b".repeat(KEY_LENGTH)).unwrap();
+        let sealed = cipher
+            .seal_secret("alice@example.edu", "0123456789abcdef")
+            .unwrap();
+
+        assert!(!sealed.contains("0123456789abcdef"));
+        assert_eq!(
+            cipher.open_secret("alice@example.edu", &sealed).unwrap(),
+            "0123456789abcdef"
+        );
+        assert!(cipher.open_secret("mallory@example.edu", &sealed).is_err());
+    }
+}
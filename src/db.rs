@@ -1,9 +1,16 @@
+use crate::clock::Clock;
 use crate::config;
-use crate::model::{Email, Token, TokenId, UpdateQueueItem, UpdateQueueKey, User};
-use anyhow::Result;
+use crate::cookies::{CookieCipher, CookieJar};
+use crate::model::{
+    is_legacy_value, session_digest, Credential, CredentialKind, Email, NotificationKind,
+    RefreshResult, SessionIndexItem, SessionKey, Stats, StatsKey, Token, TokenId, UpdateQueueItem,
+    UpdateQueueKey, User, WebhookDelivery, WebhookDeliveryKey,
+};
+use anyhow::{anyhow, Context, Result};
 use kv::TransactionError;
-use std::fmt::Write;
+use std::fmt::{Display, Formatter, Write};
 use std::result;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tracing::{debug, info, instrument, warn};
 
@@ -12,39 +19,243 @@ pub struct Database {
     users: kv::Bucket<'static, Email, User>,
     tokens: kv::Bucket<'static, TokenId, Token>,
     update_queue: kv::Bucket<'static, UpdateQueueKey, UpdateQueueItem>,
+    webhook_deliveries: kv::Bucket<'static, WebhookDeliveryKey, WebhookDelivery>,
+    stats: kv::Bucket<'static, StatsKey, Stats>,
+    /// Finds tokens by their current and previous session values, without storing the values themselves
+    sessions: kv::Bucket<'static, SessionKey, SessionIndexItem>,
+    cookie_cipher: Option<CookieCipher>,
+    clock: Arc<dyn Clock>,
 }
 
+/// What `Database::add_token` did
+pub struct AddedToken {
+    pub token_id: TokenId,
+    pub token: Token,
+    /// `false` if the session was already stored for the user
+    pub new: bool,
+    /// Tokens removed to stay within the per-user limit
+    pub evicted: Vec<(TokenId, Token)>,
+}
+
+/// The user already has as many sessions as allowed, and the limit is configured to reject new ones
+#[derive(Debug)]
+pub struct UserLimitReached {
+    pub max_sessions: usize,
+}
+
+impl Display for UserLimitReached {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The user already has the maximum of {} sessions",
+            self.max_sessions
+        )
+    }
+}
+
+impl std::error::Error for UserLimitReached {}
+
 impl Database {
-    #[instrument]
-    pub fn new(config: config::Database) -> Result<Self> {
+    #[instrument(skip(clock))]
+    pub fn new(config: config::Database, clock: Arc<dyn Clock>) -> Result<Self> {
+        let cookie_cipher = config
+            .encryption_key
+            .map(|key| CookieCipher::new(key.expose()))
+            .transpose()?;
         let db = kv::Store::new(kv::Config::new(config.path))?;
         let users = db.bucket(Some("users"))?;
         let tokens = db.bucket(Some("tokens"))?;
         let update_queue = db.bucket(Some("update_queue"))?;
+        let webhook_deliveries = db.bucket(Some("webhook_deliveries"))?;
+        let stats = db.bucket(Some("stats"))?;
+        let sessions = db.bucket(Some("sessions"))?;
 
-        Ok(Self {
+        let db = Self {
             _db: db,
             users,
             tokens,
             update_queue,
-        })
+            webhook_deliveries,
+            stats,
+            sessions,
+            cookie_cipher,
+            clock,
+        };
+        db.migrate_legacy_tokens()?;
+        db.rebuild_session_index()?;
+
+        Ok(db)
+    }
+
+    /// Tokens stored in the legacy format are queued by their deadline, which is too late to refresh them.
+    /// Re-queue them to be refreshed right away (this also re-encodes them in the current format)
+    fn migrate_legacy_tokens(&self) -> Result<()> {
+        let mut legacy_tokens = Vec::new();
+        for it in self.tokens.iter() {
+            let it = it?;
+            if is_legacy_value(&it.value::<kv::Raw>()?) {
+                legacy_tokens.push(it.key::<TokenId>()?);
+            }
+        }
+
+        if !legacy_tokens.is_empty() {
+            info!("Migrating {} legacy tokens", legacy_tokens.len());
+        }
+
+        for token_id in legacy_tokens {
+            self.reschedule_token(token_id, SystemTime::UNIX_EPOCH)?;
+        }
+
+        Ok(())
+    }
+
+    /// Indexes all tokens anew. This covers databases written before the index existed, and entries that were not
+    /// written or removed because the server stopped between a token change and its index update
+    fn rebuild_session_index(&self) -> Result<()> {
+        self.sessions.clear()?;
+        for it in self.tokens.iter() {
+            let it = it?;
+            self.index_sessions(it.key::<TokenId>()?, &it.value::<Token>()?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes the token findable by its current and previous session values
+    fn index_sessions(&self, token_id: TokenId, token: &Token) -> Result<()> {
+        for digest in token.session_digests() {
+            self.sessions
+                .set(&SessionKey(digest), &SessionIndexItem { token: token_id })?;
+        }
+
+        Ok(())
+    }
+
+    fn unindex_sessions(&self, token_id: TokenId, token: &Token) -> Result<()> {
+        self.sessions.transaction(|sessions| {
+            for digest in token.session_digests() {
+                let key = SessionKey(digest);
+                if sessions
+                    .get(&key)?
+                    .is_some_and(|item| item.token == token_id)
+                {
+                    sessions.remove(&key)?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Moves the token to another place in the update queue
+    fn reschedule_token(&self, token_id: TokenId, next_refresh: SystemTime) -> Result<()> {
+        self.tokens
+            .transaction2(&self.update_queue, |tokens, update_queue| {
+                let mut token = match tokens.get(&token_id)? {
+                    None => return Ok(()),
+                    Some(t) => t,
+                };
+
+                let old_update_key = UpdateQueueKey::try_from((token.next_refresh, token_id))?;
+                token.next_refresh = next_refresh;
+                let new_update_key = UpdateQueueKey::try_from((token.next_refresh, token_id))?;
+
+                assert!(update_queue.remove(&old_update_key)?.is_some());
+                assert!(update_queue
+                    .set(&new_update_key, &UpdateQueueItem { token: token_id })?
+                    .is_none());
+                tokens.set(&token_id, &token)?;
+
+                Ok(())
+            })?;
+
+        Ok(())
+    }
+
+    /// If more than `cluster_size` tokens are due at `now`, spreads those that can wait (i.e. have more than `margin`
+    /// left before their deadline) evenly between now and their deadlines. Returns the number of rescheduled tokens
+    #[instrument(skip(self))]
+    pub fn smooth_schedule(
+        &self,
+        now: SystemTime,
+        cluster_size: usize,
+        margin: Duration,
+    ) -> Result<usize> {
+        let mut due = Vec::new();
+        for it in self.update_queue.iter() {
+            let key = it?.key::<UpdateQueueKey>()?;
+            if key.next_refresh() > now {
+                break;
+            }
+            due.push(key.token_id());
+        }
+
+        if due.len() <= cluster_size {
+            return Ok(0);
+        }
+
+        let mut can_wait = Vec::new();
+        for token_id in due {
+            if let Some(token) = self.tokens.get(&token_id)? {
+                let latest_refresh = token.deadline.checked_sub(margin);
+                if let Some(Ok(slack)) = latest_refresh.map(|t| t.duration_since(now)) {
+                    can_wait.push((token_id, slack));
+                }
+            }
+        }
+        can_wait.sort_by_key(|&(_, slack)| slack);
+
+        let count = can_wait.len();
+        for (i, (token_id, slack)) in can_wait.into_iter().enumerate() {
+            let delay = slack.mul_f64((i + 1) as f64 / (count + 1) as f64);
+            self.reschedule_token(token_id, now + delay)?;
+        }
+
+        if count > 0 {
+            info!("Spread {} clustered tokens over their slack", count);
+        }
+
+        Ok(count)
     }
 
     #[instrument(skip_all, fields(email))]
-    pub fn add_token(&self, email: &Email, moodle_session: &str, csrf_session: &str) -> Result<()> {
-        self.users.transaction3(
+    pub fn add_token(
+        &self,
+        email: &Email,
+        credential: &Credential,
+        extend_until: Option<SystemTime>,
+        limit: &config::UserLimit,
+    ) -> Result<AddedToken> {
+        let moodle_session = credential.secret();
+        let (csrf_session, sealed_cookies, sealed_wstoken) = match credential {
+            Credential::BrowserSession {
+                csrf_session,
+                cookies,
+                ..
+            } => (
+                csrf_session.as_str(),
+                self.seal_cookies(moodle_session, cookies)?,
+                None,
+            ),
+            Credential::WebServiceToken(wstoken) => ("", None, self.seal_wstoken(email, wstoken)?),
+        };
+        let stored_session = match sealed_wstoken {
+            Some(_) => session_digest(moodle_session),
+            None => moodle_session.to_string(),
+        };
+
+        let added = self.users.transaction3(
             &self.tokens,
             &self.update_queue,
             |users, tokens, update_queue| {
                 let mut user = users.get(email)?.unwrap_or_else(|| {
                     info!("Registered user {}", email.0);
-                    User {
-                        email: email.clone(),
-                        tokens: Vec::new(),
-                    }
+                    User::new(email.clone())
                 });
 
-                let user_tokens = user
+                let mut user_tokens = user
                     .tokens
                     .iter()
                     .map(|token_id| -> result::Result<Token, TransactionError<_>> {
@@ -59,23 +270,53 @@ impl Database {
                     user_tokens.len()
                 );
 
-                if user_tokens
+                if let Some(index) = user_tokens
                     .iter()
-                    .any(|t| t.moodle_session == moodle_session)
+                    .position(|t| t.has_session(moodle_session))
                 {
                     // token already stored for this user
-                    info!("Token already stored for this user, skipping insertion");
-                    return Ok(());
+                    info!("Token already stored for this user, only updating its expiry");
+                    let token_id = user.tokens[index];
+                    let mut token = tokens.get(&token_id)?.unwrap();
+                    match token.credential {
+                        CredentialKind::BrowserSession
+                            if token.moodle_session != moodle_session =>
+                        {
+                            // the user submitted a value the session had before a rotation, and it's still valid
+                            token.rotate_session(moodle_session.to_string());
+                        }
+                        CredentialKind::BrowserSession => {}
+                        CredentialKind::WebServiceToken => {
+                            // sealed anew, in case the encryption key was set since it was stored
+                            token.moodle_session = stored_session.clone();
+                            token.sealed_wstoken = sealed_wstoken.clone();
+                        }
+                    }
+                    token.extend_until = extend_until;
+                    token.cookies = sealed_cookies.clone();
+                    tokens.set(&token_id, &token)?;
+                    return Ok(Some(AddedToken {
+                        token_id,
+                        token,
+                        new: false,
+                        evicted: Vec::new(),
+                    }));
                 }
 
-                const MAX_TOKENS_PER_USER: u32 = 3;
+                let mut evicted = Vec::new();
+                if user_tokens.len() >= limit.max_sessions {
+                    if limit.when_reached == config::WhenLimitReached::Reject {
+                        info!("User reached max of {} tokens", limit.max_sessions);
+                        return Ok(None);
+                    }
 
-                if user_tokens.len() >= MAX_TOKENS_PER_USER as usize {
                     info!(
                         "User reached max of {} tokens; removing the oldest",
-                        MAX_TOKENS_PER_USER
+                        limit.max_sessions
                     );
-
+                }
+                // the limit might have been lowered, so more than one token may have to go
+                while user_tokens.len() >= limit.max_sessions {
                     let oldest_index = user_tokens
                         .iter()
                         .enumerate()
@@ -83,13 +324,14 @@ impl Database {
                         .unwrap()
                         .0;
                     let rm_token = user.tokens.remove(oldest_index);
+                    let rm_token_value = user_tokens.remove(oldest_index);
 
-                    let deadline = user_tokens[oldest_index].deadline;
-
-                    let update_queue_key = UpdateQueueKey::from((deadline, rm_token));
+                    let update_queue_key =
+                        UpdateQueueKey::try_from((rm_token_value.next_refresh, rm_token))?;
 
                     assert!(tokens.remove(&rm_token)?.is_some());
                     assert!(update_queue.remove(&update_queue_key)?.is_some());
+                    evicted.push((rm_token, rm_token_value));
                 }
 
                 let new_token_id = TokenId::from(users.generate_id()?);
@@ -98,19 +340,19 @@ impl Database {
 
                 user.tokens.push(new_token_id);
 
-                // a lot of time ago...
-                let deadline = SystemTime::UNIX_EPOCH;
-                let added = SystemTime::now();
+                let mut token = Token::new(
+                    email.clone(),
+                    stored_session.clone(),
+                    csrf_session.to_string(),
+                    self.clock.now(),
+                );
+                token.credential = credential.kind();
+                token.extend_until = extend_until;
+                token.cookies = sealed_cookies.clone();
+                token.sealed_wstoken = sealed_wstoken.clone();
 
-                let update_queue_key = UpdateQueueKey::from((deadline, new_token_id));
-
-                let token = Token {
-                    owner: email.clone(),
-                    moodle_session: moodle_session.to_string(),
-                    csrf_session: csrf_session.to_string(),
-                    deadline,
-                    added,
-                };
+                let update_queue_key =
+                    UpdateQueueKey::try_from((token.next_refresh, new_token_id))?;
 
                 assert!(tokens.set(&new_token_id, &token)?.is_none());
                 assert!(update_queue
@@ -123,33 +365,176 @@ impl Database {
                     .is_none());
                 users.set(email, &user)?;
 
-                Ok(())
+                Ok(Some(AddedToken {
+                    token_id: new_token_id,
+                    token,
+                    new: true,
+                    evicted,
+                }))
             },
         )?;
 
-        Ok(())
+        let added = added.ok_or(UserLimitReached {
+            max_sessions: limit.max_sessions,
+        })?;
+
+        // kv transactions span at most three buckets, so the index is updated right after. Lookups check the token
+        // they find, and the index is rebuilt on start in case the server stopped in between
+        for (token_id, token) in &added.evicted {
+            self.unindex_sessions(*token_id, token)?;
+        }
+        self.index_sessions(added.token_id, &added.token)?;
+
+        Ok(added)
+    }
+
+    /// Encrypts the cookies other than the session one. Returns `None` if there are none to store
+    fn seal_cookies(&self, moodle_session: &str, cookies: &CookieJar) -> Result<Option<String>> {
+        if cookies.others_len() == 0 {
+            return Ok(None);
+        }
+
+        match &self.cookie_cipher {
+            Some(cipher) => Ok(Some(cipher.seal(moodle_session, cookies)?)),
+            None => {
+                warn!(
+                    "database.encryption_key is not set, dropping {} cookies",
+                    cookies.others_len()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Encrypts the web service token. Returns `None` if there is no key, then the token is stored as it is
+    fn seal_wstoken(&self, email: &Email, wstoken: &str) -> Result<Option<String>> {
+        self.cookie_cipher
+            .as_ref()
+            .map(|cipher| cipher.seal_secret(&email.0, wstoken))
+            .transpose()
+    }
+
+    /// The token credential, with the stored cookies and web service token decrypted
+    pub fn credential(&self, token: &Token) -> Result<Credential> {
+        Ok(match token.credential {
+            CredentialKind::BrowserSession => Credential::BrowserSession {
+                moodle_session: token.moodle_session.clone(),
+                csrf_session: token.csrf_session.clone(),
+                cookies: self.cookie_jar(token)?,
+            },
+            CredentialKind::WebServiceToken => Credential::WebServiceToken(self.wstoken(token)?),
+        })
     }
 
+    fn wstoken(&self, token: &Token) -> Result<String> {
+        Ok(match (&token.sealed_wstoken, &self.cookie_cipher) {
+            (None, _) => token.moodle_session.clone(),
+            (Some(sealed), Some(cipher)) => cipher
+                .open_secret(&token.owner.0, sealed)
+                .context("Opening the stored web service token")?,
+            (Some(_), None) => {
+                return Err(anyhow!(
+                    "The token has an encrypted web service token, but database.encryption_key is not set"
+                ))
+            }
+        })
+    }
+
+    /// All the cookies to send to moodle for the token
+    fn cookie_jar(&self, token: &Token) -> Result<CookieJar> {
+        Ok(match (&token.cookies, &self.cookie_cipher) {
+            (None, _) => CookieJar::new(&token.moodle_session),
+            (Some(sealed), Some(cipher)) => cipher
+                .open(&token.moodle_session, sealed)
+                .context("Opening the stored cookies")?,
+            (Some(_), None) => {
+                return Err(anyhow!(
+                    "The token has encrypted cookies, but database.encryption_key is not set"
+                ))
+            }
+        })
+    }
+
+    /// Stores the cookies after moodle changed them. If moodle issued a new session cookie, the token switches to it.
+    /// Returns the updated token, or `None` if it was removed in the meantime
+    #[instrument(skip(self, cookies))]
+    pub fn update_cookies(&self, token_id: TokenId, cookies: &CookieJar) -> Result<Option<Token>> {
+        let moodle_session = match cookies.session() {
+            Some(s) => s,
+            None => {
+                warn!("Moodle removed the session cookie, keeping the stored cookies");
+                return Ok(self.tokens.get(&token_id)?);
+            }
+        };
+        let sealed_cookies = self.seal_cookies(moodle_session, cookies)?;
+
+        let token = self
+            .tokens
+            .transaction2(&self.sessions, |tokens, sessions| {
+                let mut token = match tokens.get(&token_id)? {
+                    None => return Ok(None),
+                    Some(t) => t,
+                };
+
+                if token.moodle_session != moodle_session {
+                    info!("Moodle rotated the session cookie of {:?}", token_id);
+                    if let Some(dropped) = token.rotate_session(moodle_session.to_string()) {
+                        sessions.remove(&SessionKey(dropped))?;
+                    }
+                    token.rotated_at = Some(self.clock.now());
+                    sessions.set(
+                        &SessionKey(session_digest(moodle_session)),
+                        &SessionIndexItem { token: token_id },
+                    )?;
+                }
+                token.cookies = sealed_cookies.clone();
+                tokens.set(&token_id, &token)?;
+
+                Ok(Some(token))
+            })?;
+
+        Ok(token)
+    }
+
+    /// Returns the updated token, or `None` if it was removed in the meantime
     #[instrument(skip(self))]
-    pub fn update_token(&self, token_id: TokenId, new_time_left: Duration) -> Result<()> {
-        self.tokens
+    pub fn update_token(
+        &self,
+        token_id: TokenId,
+        new_time_left: Duration,
+        refresh_in: Duration,
+    ) -> Result<Option<Token>> {
+        let token = self
+            .tokens
             .transaction2(&self.update_queue, |tokens, update_queue| {
                 let mut token = match tokens.get(&token_id)? {
                     None => {
                         // token was removed while we were fiddling with it seems. This is ok, we just ignore it
                         info!("{:?} was removed while it was being updated", token_id);
-                        return Ok(());
+                        return Ok(None);
                     }
                     Some(t) => t,
                 };
 
-                let now = SystemTime::now();
+                let now = self.clock.now();
                 let new_deadline = now + new_time_left;
+                let mut new_next_refresh = now + refresh_in;
+                if let Some(extend_until) = token.extend_until {
+                    // wake up in time to drop the token when the user wants it to stop
+                    new_next_refresh = new_next_refresh.min(extend_until);
+                }
 
-                let old_update_key = UpdateQueueKey::from((token.deadline, token_id));
-                let new_update_key = UpdateQueueKey::from((new_deadline, token_id));
+                let old_update_key = UpdateQueueKey::try_from((token.next_refresh, token_id))?;
+                let new_update_key = UpdateQueueKey::try_from((new_next_refresh, token_id))?;
 
                 token.deadline = new_deadline;
+                token.next_refresh = new_next_refresh;
+                token.record_outcome(
+                    now,
+                    RefreshResult::Extended {
+                        time_left: new_time_left,
+                    },
+                );
 
                 assert!(update_queue.remove(&old_update_key)?.is_some());
                 assert!(update_queue
@@ -157,24 +542,147 @@ impl Database {
                     .is_none());
                 tokens.set(&token_id, &token)?;
 
-                Ok(())
+                Ok(Some(token))
             })?;
 
+        Ok(token)
+    }
+
+    /// Records a failed (or timed out) refresh attempt and returns the updated token. The token stays where it is in
+    /// the update queue
+    #[instrument(skip(self))]
+    pub fn record_token_failure(
+        &self,
+        token_id: TokenId,
+        error: String,
+        timed_out: bool,
+    ) -> Result<Option<Token>> {
+        let token = self.tokens.transaction(|tokens| {
+            let mut token = match tokens.get(&token_id)? {
+                None => return Ok(None),
+                Some(t) => t,
+            };
+
+            let error = error.clone();
+            token.record_outcome(
+                self.clock.now(),
+                if timed_out {
+                    RefreshResult::TimedOut { error }
+                } else {
+                    RefreshResult::Failed { error }
+                },
+            );
+            tokens.set(&token_id, &token)?;
+
+            Ok(Some(token))
+        })?;
+
+        Ok(token)
+    }
+
+    /// Counts a refresh that started after the deadline, for the token and for the server. Returns the token and the
+    /// number of missed deadlines of the server
+    #[instrument(skip(self))]
+    pub fn record_missed_deadline(&self, token_id: TokenId) -> Result<(Option<Token>, u64)> {
+        let result = self.tokens.transaction2(&self.stats, |tokens, stats| {
+            let mut counters = stats.get(&StatsKey)?.unwrap_or_default();
+            counters.missed_deadlines += 1;
+            stats.set(&StatsKey, &counters)?;
+
+            let token = match tokens.get(&token_id)? {
+                None => None,
+                Some(mut token) => {
+                    token.missed_deadlines += 1;
+                    tokens.set(&token_id, &token)?;
+                    Some(token)
+                }
+            };
+
+            Ok((token, counters.missed_deadlines))
+        })?;
+
+        Ok(result)
+    }
+
+    pub fn get_stats(&self) -> Result<Stats> {
+        Ok(self.stats.get(&StatsKey)?.unwrap_or_default())
+    }
+
+    /// Postpones the next refresh attempt, e.g. when moodle timed out and retrying right away would likely time out too
+    #[instrument(skip(self))]
+    pub fn retry_token_at(&self, token_id: TokenId, at: SystemTime) -> Result<()> {
+        self.reschedule_token(token_id, at)
+    }
+
+    pub fn get_user(&self, email: &Email) -> Result<Option<User>> {
+        Ok(self.users.get(email)?)
+    }
+
+    /// Returns `false` if the user is not known
+    #[instrument(skip(self))]
+    pub fn set_notifications_opted_out(&self, email: &Email, opted_out: bool) -> Result<bool> {
+        let found = self.users.transaction(|users| {
+            let mut user = match users.get(email)? {
+                None => return Ok(false),
+                Some(u) => u,
+            };
+
+            user.notifications_opted_out = opted_out;
+            users.set(email, &user)?;
+
+            Ok(true)
+        })?;
+
+        Ok(found)
+    }
+
+    #[instrument(skip(self))]
+    pub fn record_notification(
+        &self,
+        email: &Email,
+        kind: NotificationKind,
+        at: SystemTime,
+    ) -> Result<()> {
+        self.users.transaction(|users| {
+            if let Some(mut user) = users.get(email)? {
+                user.last_notified.insert(kind, at);
+                users.set(email, &user)?;
+            }
+
+            Ok(())
+        })?;
+
         Ok(())
     }
 
+    /// Finds the token by the current value of the session cookie, or by one it had before moodle rotated it
+    pub fn find_token_by_session(&self, moodle_session: &str) -> Result<Option<(TokenId, Token)>> {
+        let key = SessionKey(session_digest(moodle_session));
+        let token_id = match self.sessions.get(&key)? {
+            Some(item) => item.token,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .tokens
+            .get(&token_id)?
+            .filter(|token| token.has_session(moodle_session))
+            .map(|token| (token_id, token)))
+    }
+
+    /// Returns the removed token, or `None` if it was already gone
     #[instrument(skip(self))]
-    pub fn remove_token(&self, token_id: TokenId) -> Result<()> {
-        self.users.transaction3(
+    pub fn remove_token(&self, token_id: TokenId) -> Result<Option<Token>> {
+        let token = self.users.transaction3(
             &self.tokens,
             &self.update_queue,
             |users, tokens, update_queue| {
                 let token = match tokens.remove(&token_id)? {
                     Some(v) => v,
-                    None => return Ok(()),
+                    None => return Ok(None),
                 };
 
-                let update_queue_key = UpdateQueueKey::from((token.deadline, token_id));
+                let update_queue_key = UpdateQueueKey::try_from((token.next_refresh, token_id))?;
 
                 assert!(update_queue.remove(&update_queue_key)?.is_some());
 
@@ -182,11 +690,15 @@ impl Database {
                 user.tokens.retain(|t| t != &token_id);
                 users.set(&token.owner, &user)?;
 
-                Ok(())
+                Ok(Some(token))
             },
         )?;
 
-        Ok(())
+        if let Some(token) = &token {
+            self.unindex_sessions(token_id, token)?;
+        }
+
+        Ok(token)
     }
 
     pub fn subscribe_queue_updates(&self) -> Result<kv::Watch<UpdateQueueKey, UpdateQueueItem>> {
@@ -211,10 +723,64 @@ impl Database {
         }
     }
 
+    /// The clock the schedule is kept by
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
     pub fn get_token_count(&self) -> Result<usize> {
         Ok(self.tokens.len())
     }
 
+    #[instrument(skip(self, delivery), fields(url = %delivery.url))]
+    pub fn add_webhook_delivery(&self, delivery: WebhookDelivery) -> Result<()> {
+        self.webhook_deliveries.transaction(|deliveries| {
+            let id = deliveries.generate_id()?;
+            let key = WebhookDeliveryKey::try_from((self.clock.now(), id))?;
+            deliveries.set(&key, &delivery)?;
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    pub fn next_webhook_delivery(&self) -> Result<Option<(WebhookDeliveryKey, WebhookDelivery)>> {
+        Ok(match self.webhook_deliveries.first()? {
+            None => None,
+            Some(it) => Some((it.key()?, it.value()?)),
+        })
+    }
+
+    /// Moves the delivery to `next_attempt`, keeping its id
+    pub fn reschedule_webhook_delivery(
+        &self,
+        key: WebhookDeliveryKey,
+        delivery: &WebhookDelivery,
+        next_attempt: SystemTime,
+    ) -> Result<()> {
+        self.webhook_deliveries.transaction(|deliveries| {
+            deliveries.remove(&key)?;
+            let new_key = WebhookDeliveryKey::try_from((next_attempt, key.delivery_id()))?;
+            deliveries.set(&new_key, delivery)?;
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    pub fn remove_webhook_delivery(&self, key: WebhookDeliveryKey) -> Result<()> {
+        self.webhook_deliveries.remove(&key)?;
+        Ok(())
+    }
+
+    pub fn subscribe_webhook_deliveries(
+        &self,
+    ) -> Result<kv::Watch<WebhookDeliveryKey, WebhookDelivery>> {
+        Ok(self.webhook_deliveries.watch_prefix(None)?)
+    }
+
     #[allow(unused)]
     pub fn dump(&self) -> Result<String> {
         let mut res = String::new();
@@ -253,3 +819,164 @@ impl Database {
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::model::unix_millis;
+    use tempfile::TempDir;
+
+    fn database() -> (TempDir, Database) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(
+            config::Database {
+                path: dir.path().join("db").try_into().unwrap(),
+                encryption_key: None,
+            },
+            Arc::new(SystemClock),
+        )
+        .unwrap();
+        (dir, db)
+    }
+
+    fn add(db: &Database, moodle_session: &str) -> TokenId {
+        add_for(db, "student@example.edu", moodle_session)
+    }
+
+    fn add_for(db: &Database, email: &str, moodle_session: &str) -> TokenId {
+        let credential = Credential::BrowserSession {
+            moodle_session: moodle_session.to_string(),
+            csrf_session: "s3ssk3y".to_string(),
+            cookies: CookieJar::new(moodle_session),
+        };
+        let email = Email(email.to_string());
+        db.add_token(&email, &credential, None, &config::UserLimit::default())
+            .unwrap()
+            .token_id
+    }
+
+    /// Adds a token that was refreshed with `time_left` and is due again right away
+    fn add_due(db: &Database, i: usize, time_left: Duration) -> TokenId {
+        let token_id = add_for(db, &format!("student{}@example.edu", i), &i.to_string());
+        db.update_token(token_id, time_left, Duration::ZERO)
+            .unwrap();
+        token_id
+    }
+
+    fn next_refresh(db: &Database, token_id: TokenId) -> SystemTime {
+        db.tokens.get(&token_id).unwrap().unwrap().next_refresh
+    }
+
+    fn found(db: &Database, moodle_session: &str) -> Option<TokenId> {
+        db.find_token_by_session(moodle_session)
+            .unwrap()
+            .map(|(token_id, _)| token_id)
+    }
+
+    #[test]
+    fn finds_tokens_by_current_and_previous_sessions() {
+        let (_dir, db) = database();
+        let token_id = add(&db, "first");
+        assert_eq!(found(&db, "first"), Some(token_id));
+        assert_eq!(found(&db, "other"), None);
+
+        let token = db
+            .update_cookies(token_id, &CookieJar::new("second"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.moodle_session, "second");
+        assert!(token.rotated_at.is_some());
+        assert_eq!(token.previous_session_digests, [session_digest("first")]);
+        assert_eq!(found(&db, "first"), Some(token_id));
+        assert_eq!(found(&db, "second"), Some(token_id));
+
+        db.remove_token(token_id).unwrap();
+        assert_eq!(found(&db, "first"), None);
+        assert_eq!(found(&db, "second"), None);
+    }
+
+    #[test]
+    fn forgets_sessions_rotated_out_of_the_history() {
+        let (_dir, db) = database();
+        // five previous values are kept
+        let token_id = add(&db, "0");
+        for i in 1..=6 {
+            db.update_cookies(token_id, &CookieJar::new(&i.to_string()))
+                .unwrap();
+        }
+
+        assert_eq!(found(&db, "0"), None);
+        assert_eq!(found(&db, "1"), Some(token_id));
+        assert_eq!(db.sessions.len(), 6);
+    }
+
+    #[test]
+    fn evicted_tokens_are_not_found() {
+        let (_dir, db) = database();
+        let first = add(&db, "0");
+        for i in 1..=config::UserLimit::default().max_sessions {
+            add(&db, &i.to_string());
+        }
+
+        assert_eq!(found(&db, "0"), None);
+        assert!(db.tokens.get(&first).unwrap().is_none());
+        assert!(found(&db, "1").is_some());
+    }
+
+    #[test]
+    fn smoothing_spreads_a_cluster_over_the_slack() {
+        let (_dir, db) = database();
+        let minute = Duration::from_secs(60);
+        let waiting = (0..5)
+            .map(|i| add_due(&db, i, 30 * minute))
+            .collect::<Vec<_>>();
+        let urgent = add_due(&db, 5, 2 * minute);
+        let now = db.clock().now();
+
+        assert_eq!(db.smooth_schedule(now, 3, 5 * minute).unwrap(), 5);
+
+        assert!(next_refresh(&db, urgent) <= now);
+        let mut spread = waiting
+            .iter()
+            .map(|&token_id| next_refresh(&db, token_id))
+            .collect::<Vec<_>>();
+        spread.sort();
+        spread.dedup();
+        assert_eq!(spread.len(), 5);
+        let latest = db.tokens.get(&waiting[0]).unwrap().unwrap().deadline - 5 * minute;
+        assert!(spread.iter().all(|&t| t > now && t < latest));
+    }
+
+    #[test]
+    fn smoothing_leaves_small_clusters_alone() {
+        let (_dir, db) = database();
+        let minute = Duration::from_secs(60);
+        let tokens = (0..3)
+            .map(|i| add_due(&db, i, 30 * minute))
+            .collect::<Vec<_>>();
+        let now = db.clock().now();
+
+        assert_eq!(db.smooth_schedule(now, 3, 5 * minute).unwrap(), 0);
+        assert!(tokens
+            .iter()
+            .all(|&token_id| next_refresh(&db, token_id) <= now));
+    }
+
+    #[test]
+    fn rescheduling_moves_the_token_in_the_queue() {
+        let (_dir, db) = database();
+        let first = add(&db, "first");
+        let second = add(&db, "second");
+        assert_eq!(db.get_most_urgent_token().unwrap().unwrap().0, first);
+
+        // tokens are stored with millisecond precision
+        let later =
+            SystemTime::UNIX_EPOCH + Duration::from_millis(unix_millis(db.clock().now()) + 60_000);
+        db.retry_token_at(first, later).unwrap();
+
+        assert_eq!(db.get_most_urgent_token().unwrap().unwrap().0, second);
+        assert_eq!(next_refresh(&db, first), later);
+        assert_eq!(db.update_queue.len(), 2);
+    }
+}
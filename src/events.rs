@@ -0,0 +1,204 @@
+use crate::config;
+use crate::model::{unix_millis, Token, TokenId};
+use crate::webhook::Webhooks;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+
+/// How many events a subscriber can fall behind before it starts missing them
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RevokeReason {
+    /// The user asked to stop extending the session
+    Requested,
+    /// The time the user chose to extend the session until has come
+    Expired,
+}
+
+/// Session lifecycle events, as sent to webhooks and subscribers. All times are in milliseconds since the unix epoch
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TokenRegistered {
+        token_id: u64,
+        email: String,
+        extend_until: Option<u64>,
+    },
+    TokenEvicted {
+        token_id: u64,
+        email: String,
+    },
+    TokenRefreshed {
+        token_id: u64,
+        email: String,
+        deadline: u64,
+        next_refresh: u64,
+    },
+    /// A timed out refresh is retried shortly and doesn't count toward `consecutive_failures`
+    TokenRefreshFailed {
+        token_id: u64,
+        email: String,
+        error: String,
+        timed_out: bool,
+        consecutive_failures: u32,
+    },
+    TokenDied {
+        token_id: u64,
+        email: String,
+    },
+    TokenRevoked {
+        token_id: u64,
+        email: String,
+        reason: RevokeReason,
+    },
+    /// Moodle issued a new session cookie. The new value is only sent to the user's own event streams, not to webhooks
+    TokenSessionRotated {
+        token_id: u64,
+        email: String,
+        #[serde(skip)]
+        moodle_session: config::Secret,
+    },
+    /// The refresh started after the deadline, so the session may have expired
+    TokenDeadlineMissed {
+        token_id: u64,
+        email: String,
+        deadline: u64,
+        late_by_ms: u64,
+    },
+}
+
+impl Event {
+    pub fn registered(token_id: TokenId, token: &Token) -> Self {
+        Self::TokenRegistered {
+            token_id: token_id.into(),
+            email: token.owner.0.clone(),
+            extend_until: token.extend_until.map(unix_millis),
+        }
+    }
+
+    pub fn evicted(token_id: TokenId, token: &Token) -> Self {
+        Self::TokenEvicted {
+            token_id: token_id.into(),
+            email: token.owner.0.clone(),
+        }
+    }
+
+    pub fn refreshed(token_id: TokenId, token: &Token) -> Self {
+        Self::TokenRefreshed {
+            token_id: token_id.into(),
+            email: token.owner.0.clone(),
+            deadline: unix_millis(token.deadline),
+            next_refresh: unix_millis(token.next_refresh),
+        }
+    }
+
+    pub fn refresh_failed(token_id: TokenId, token: &Token, timed_out: bool) -> Self {
+        Self::TokenRefreshFailed {
+            token_id: token_id.into(),
+            email: token.owner.0.clone(),
+            error: token.last_error.clone().unwrap_or_default(),
+            timed_out,
+            consecutive_failures: token.consecutive_failures,
+        }
+    }
+
+    pub fn died(token_id: TokenId, token: &Token) -> Self {
+        Self::TokenDied {
+            token_id: token_id.into(),
+            email: token.owner.0.clone(),
+        }
+    }
+
+    pub fn revoked(token_id: TokenId, token: &Token, reason: RevokeReason) -> Self {
+        Self::TokenRevoked {
+            token_id: token_id.into(),
+            email: token.owner.0.clone(),
+            reason,
+        }
+    }
+
+    pub fn session_rotated(token_id: TokenId, token: &Token) -> Self {
+        Self::TokenSessionRotated {
+            token_id: token_id.into(),
+            email: token.owner.0.clone(),
+            moodle_session: token.moodle_session.clone().into(),
+        }
+    }
+
+    pub fn deadline_missed(token_id: TokenId, token: &Token, late_by: Duration) -> Self {
+        Self::TokenDeadlineMissed {
+            token_id: token_id.into(),
+            email: token.owner.0.clone(),
+            deadline: unix_millis(token.deadline),
+            late_by_ms: late_by.as_millis() as u64,
+        }
+    }
+
+    pub fn email(&self) -> &str {
+        match self {
+            Event::TokenRegistered { email, .. }
+            | Event::TokenEvicted { email, .. }
+            | Event::TokenRefreshed { email, .. }
+            | Event::TokenRefreshFailed { email, .. }
+            | Event::TokenDied { email, .. }
+            | Event::TokenRevoked { email, .. }
+            | Event::TokenSessionRotated { email, .. }
+            | Event::TokenDeadlineMissed { email, .. } => email,
+        }
+    }
+
+    pub fn kind(&self) -> config::WebhookEvent {
+        match self {
+            Event::TokenRegistered { .. } => config::WebhookEvent::TokenRegistered,
+            Event::TokenEvicted { .. } => config::WebhookEvent::TokenEvicted,
+            Event::TokenRefreshed { .. } => config::WebhookEvent::TokenRefreshed,
+            Event::TokenRefreshFailed { .. } => config::WebhookEvent::TokenRefreshFailed,
+            Event::TokenDied { .. } => config::WebhookEvent::TokenDied,
+            Event::TokenRevoked { .. } => config::WebhookEvent::TokenRevoked,
+            Event::TokenSessionRotated { .. } => config::WebhookEvent::TokenSessionRotated,
+            Event::TokenDeadlineMissed { .. } => config::WebhookEvent::TokenDeadlineMissed,
+        }
+    }
+}
+
+/// An event together with the time it happened (in milliseconds since the unix epoch)
+#[derive(Debug, Serialize)]
+pub struct Payload {
+    #[serde(flatten)]
+    pub event: Event,
+    pub at: u64,
+}
+
+/// Distributes session lifecycle events to webhooks and live subscribers
+pub struct Events {
+    webhooks: Webhooks,
+    subscribers: broadcast::Sender<Arc<Payload>>,
+}
+
+impl Events {
+    pub fn new(webhooks: Webhooks) -> Self {
+        let (subscribers, _) = broadcast::channel(SUBSCRIBER_CAPACITY);
+        Self {
+            webhooks,
+            subscribers,
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        let payload = Arc::new(Payload {
+            event,
+            at: unix_millis(SystemTime::now()),
+        });
+
+        self.webhooks.deliver(&payload);
+        // it's fine if nobody is listening
+        let _ = self.subscribers.send(payload);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Payload>> {
+        self.subscribers.subscribe()
+    }
+}
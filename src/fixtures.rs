@@ -0,0 +1,328 @@
+use crate::config;
+use anyhow::{anyhow, Context};
+use camino::Utf8PathBuf;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::header::{HeaderName, HeaderValue, SET_COOKIE};
+use reqwest::{Request, Response, Url};
+use reqwest_middleware::{Middleware, Next};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use task_local_extensions::Extensions;
+use tracing::{debug, info, warn};
+
+const REDACTED: &str = "REDACTED";
+
+/// Secrets in URLs, form bodies and links, e.g. `sesskey=abc`
+static QUERY_SECRET_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"((?:sesskey|wstoken)=)[^&"'\s<]+"#).unwrap());
+/// Secrets in JSON, e.g. `"sesskey":"abc"` in the page config
+static JSON_SECRET_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"("(?:sesskey|wstoken|token|privatetoken)"\s*:\s*")[^"]*"#).unwrap());
+
+fn redact(text: &str) -> String {
+    let text = QUERY_SECRET_REGEX.replace_all(text, format!("${{1}}{}", REDACTED));
+    JSON_SECRET_REGEX
+        .replace_all(&text, format!("${{1}}{}", REDACTED))
+        .into_owned()
+}
+
+/// Keeps the name and attributes of the cookie, but not its value
+fn redact_set_cookie(header: &str) -> String {
+    match header.split_once('=') {
+        Some((name, rest)) => {
+            let attributes = rest.find(';').map(|i| &rest[i..]).unwrap_or_default();
+            format!("{}={}{}", name, REDACTED, attributes)
+        }
+        None => header.to_string(),
+    }
+}
+
+/// What identifies a request. Fixtures are replayed for requests that look the same after redaction
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    /// Relative to the base URL, so that fixtures don't depend on where moodle was when they were recorded
+    path: String,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+impl RecordedResponse {
+    fn to_response(&self) -> anyhow::Result<Response> {
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+
+        Ok(Response::from(builder.body(self.body.clone())?))
+    }
+}
+
+enum Mode {
+    Record {
+        next_index: AtomicUsize,
+    },
+    /// Each interaction is served once, in the order of recording. Once all matching ones were served, the last one
+    /// is served again, so that repeated keep-alives work
+    Replay {
+        interactions: Mutex<Vec<(Interaction, bool)>>,
+    },
+}
+
+/// Records moodle interactions to fixture files, or serves them back instead of talking to moodle
+pub struct Fixtures {
+    base_url: Url,
+    directory: Utf8PathBuf,
+    mode: Mode,
+}
+
+impl Fixtures {
+    pub fn new(config: &config::Fixtures, base_url: Url) -> anyhow::Result<Self> {
+        let directory = config.directory.clone();
+
+        let mode = match config.mode {
+            config::FixtureMode::Record => {
+                std::fs::create_dir_all(&directory)
+                    .with_context(|| format!("Creating the fixture directory {}", directory))?;
+                warn!(
+                    "Recording moodle interactions to {}. Secrets are redacted, but the fixtures still contain \
+                     personal data like email addresses, review them before sharing",
+                    directory
+                );
+                Mode::Record {
+                    next_index: AtomicUsize::new(Self::files(&directory)?.len()),
+                }
+            }
+            config::FixtureMode::Replay => {
+                let interactions = Self::files(&directory)?
+                    .into_iter()
+                    .map(|path| {
+                        let content = std::fs::read_to_string(&path)
+                            .with_context(|| format!("Reading fixture {}", path))?;
+                        let interaction: Interaction = serde_json::from_str(&content)
+                            .with_context(|| format!("Parsing fixture {}", path))?;
+                        Ok((interaction, false))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                info!(
+                    "Replaying {} moodle interactions from {} instead of talking to moodle",
+                    interactions.len(),
+                    directory
+                );
+                Mode::Replay {
+                    interactions: Mutex::new(interactions),
+                }
+            }
+        };
+
+        Ok(Self {
+            base_url,
+            directory,
+            mode,
+        })
+    }
+
+    /// Fixture files in the order they were recorded
+    fn files(directory: &Utf8PathBuf) -> anyhow::Result<Vec<Utf8PathBuf>> {
+        let mut files = Vec::new();
+        for entry in directory
+            .read_dir_utf8()
+            .with_context(|| format!("Listing the fixture directory {}", directory))?
+        {
+            let path = entry?.into_path();
+            if path.extension() == Some("json") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn recorded_request(&self, req: &Request) -> RecordedRequest {
+        let url = req.url().as_str();
+        let path = url.strip_prefix(self.base_url.as_str()).unwrap_or(url);
+        let body = req
+            .body()
+            .and_then(|b| b.as_bytes())
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default();
+
+        RecordedRequest {
+            method: req.method().to_string(),
+            path: redact(path),
+            body: redact(&body),
+        }
+    }
+
+    fn replay(
+        &self,
+        interactions: &Mutex<Vec<(Interaction, bool)>>,
+        request: &RecordedRequest,
+    ) -> anyhow::Result<Response> {
+        let mut interactions = interactions.lock().unwrap();
+
+        let matching = || {
+            interactions
+                .iter()
+                .enumerate()
+                .filter(|(_, (i, _))| &i.request == request)
+        };
+        let index = matching()
+            .find(|(_, (_, served))| !served)
+            .or_else(|| matching().next_back())
+            .map(|(index, _)| index)
+            .ok_or_else(|| anyhow!("No fixture for {} {}", request.method, request.path))?;
+
+        let (interaction, served) = &mut interactions[index];
+        *served = true;
+        debug!("Replaying fixture #{} for {}", index, request.path);
+        interaction.response.to_response()
+    }
+
+    async fn record(
+        &self,
+        next_index: &AtomicUsize,
+        request: RecordedRequest,
+        resp: Response,
+    ) -> reqwest_middleware::Result<Response> {
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = resp.bytes().await?;
+
+        let interaction = Interaction {
+            request,
+            response: RecordedResponse {
+                status: status.as_u16(),
+                headers: headers
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        let value = value.to_str().ok()?;
+                        Some((
+                            name.to_string(),
+                            if name == SET_COOKIE {
+                                redact_set_cookie(value)
+                            } else {
+                                // e.g. a `Location` carrying the sesskey
+                                redact(value)
+                            },
+                        ))
+                    })
+                    .collect(),
+                body: redact(&String::from_utf8_lossy(&body)),
+            },
+        };
+
+        let path = self.directory.join(format!(
+            "{:04}.json",
+            next_index.fetch_add(1, Ordering::SeqCst)
+        ));
+        let written = serde_json::to_string_pretty(&interaction)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(std::fs::write(&path, json)?));
+        if let Err(e) = written {
+            warn!("Failed to write fixture {}: {:#}", path, e);
+        }
+
+        // the body was consumed, so the response is rebuilt from what was read
+        let mut rebuilt = http::Response::builder().status(status);
+        if let Some(h) = rebuilt.headers_mut() {
+            *h = headers;
+        }
+        Ok(Response::from(
+            rebuilt.body(body).map_err(anyhow::Error::from)?,
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Fixtures {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let request = self.recorded_request(&req);
+
+        match &self.mode {
+            Mode::Replay { interactions } => Ok(self.replay(interactions, &request)?),
+            Mode::Record { next_index } => {
+                let resp = next.run(req, extensions).await?;
+                self.record(next_index, request, resp).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_query_parameters() {
+        assert_eq!(
+            redact("lib/ajax/service.php?sesskey=aBc123XyZ&info=core_session_touch"),
+            "lib/ajax/service.php?sesskey=REDACTED&info=core_session_touch"
+        );
+        assert_eq!(
+            redact(r#"<a href="https://moodle.example.edu/login/logout.php?sesskey=aBc123XyZ">"#),
+            r#"<a href="https://moodle.example.edu/login/logout.php?sesskey=REDACTED">"#
+        );
+        assert_eq!(
+            redact("webservice/rest/server.php?wstoken=0123456789abcdef"),
+            "webservice/rest/server.php?wstoken=REDACTED"
+        );
+    }
+
+    #[test]
+    fn redacts_json_fields() {
+        assert_eq!(
+            redact(
+                r#"M.cfg = {"wwwroot":"https:\/\/moodle.example.edu","sesskey":"aBc123XyZ","theme":"boost"};"#
+            ),
+            r#"M.cfg = {"wwwroot":"https:\/\/moodle.example.edu","sesskey":"REDACTED","theme":"boost"};"#
+        );
+        assert_eq!(
+            redact(r#"{"token": "0123456789abcdef", "privatetoken":"secret"}"#),
+            r#"{"token": "REDACTED", "privatetoken":"REDACTED"}"#
+        );
+    }
+
+    #[test]
+    fn keeps_text_without_secrets() {
+        let text = r#"[{"error":false,"data":{"userid":42,"timeremaining":7140}}]"#;
+        assert_eq!(redact(text), text);
+    }
+
+    #[test]
+    fn redacts_cookie_values_but_keeps_attributes() {
+        assert_eq!(
+            redact_set_cookie("MoodleSession=4k1b2v3c; path=/moodle/; secure; HttpOnly"),
+            "MoodleSession=REDACTED; path=/moodle/; secure; HttpOnly"
+        );
+        assert_eq!(
+            redact_set_cookie("MOODLEID1_=%25ED%25C3%251C; expires=Thu, 18-Oct-2027 09:12:44 GMT"),
+            "MOODLEID1_=REDACTED; expires=Thu, 18-Oct-2027 09:12:44 GMT"
+        );
+        assert_eq!(redact_set_cookie("lb=a=b=c"), "lb=REDACTED");
+        assert_eq!(redact_set_cookie("garbage"), "garbage");
+    }
+}
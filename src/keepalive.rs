@@ -0,0 +1,267 @@
+use crate::cookies::CookieJar;
+use crate::model::Credential;
+use crate::moodle::SessionUpdateResult;
+use crate::{config, Moodle};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::time::Duration;
+
+/// A way to make moodle see a credential in use, so that it doesn't expire
+#[async_trait]
+pub trait KeepAlive: Send + Sync {
+    /// Cookies moodle sets along the way are merged into the credential
+    async fn keep_alive(
+        &self,
+        moodle: &Moodle,
+        credential: &mut Credential,
+    ) -> Result<SessionUpdateResult>;
+}
+
+/// Creates the strategy browser sessions are kept alive with
+pub fn from_config(config: &config::KeepAlive) -> Box<dyn KeepAlive> {
+    match config {
+        config::KeepAlive::AjaxTouch => Box::new(AjaxTouch),
+        config::KeepAlive::PageFetch {
+            path,
+            session_timeout,
+        } => Box::new(PageFetch {
+            path: path.clone(),
+            session_timeout: *session_timeout,
+        }),
+    }
+}
+
+fn browser_session(credential: &mut Credential) -> Result<(&mut CookieJar, &str)> {
+    match credential {
+        Credential::BrowserSession {
+            cookies,
+            csrf_session,
+            ..
+        } => Ok((cookies, csrf_session)),
+        Credential::WebServiceToken(_) => Err(anyhow!(
+            "A web service token can't be kept alive as a browser session"
+        )),
+    }
+}
+
+pub struct AjaxTouch;
+
+#[async_trait]
+impl KeepAlive for AjaxTouch {
+    async fn keep_alive(
+        &self,
+        moodle: &Moodle,
+        credential: &mut Credential,
+    ) -> Result<SessionUpdateResult> {
+        let (cookies, csrf_session) = browser_session(credential)?;
+        moodle.update_session(cookies, csrf_session).await
+    }
+}
+
+pub struct PageFetch {
+    path: String,
+    session_timeout: Duration,
+}
+
+#[async_trait]
+impl KeepAlive for PageFetch {
+    async fn keep_alive(
+        &self,
+        moodle: &Moodle,
+        credential: &mut Credential,
+    ) -> Result<SessionUpdateResult> {
+        let (cookies, _) = browser_session(credential)?;
+        Ok(if moodle.fetch_page(cookies, &self.path).await? {
+            SessionUpdateResult::Ok {
+                time_left: self.session_timeout,
+            }
+        } else {
+            SessionUpdateResult::SessionDead
+        })
+    }
+}
+
+/// Web service tokens don't time out, so they are reported to have `interval` left after every call
+pub struct WebServicePing {
+    pub interval: Duration,
+}
+
+#[async_trait]
+impl KeepAlive for WebServicePing {
+    async fn keep_alive(
+        &self,
+        moodle: &Moodle,
+        credential: &mut Credential,
+    ) -> Result<SessionUpdateResult> {
+        let wstoken = match credential {
+            Credential::WebServiceToken(wstoken) => wstoken,
+            Credential::BrowserSession { .. } => {
+                return Err(anyhow!(
+                    "A browser session can't be kept alive with web service calls"
+                ))
+            }
+        };
+
+        Ok(if moodle.ping_web_service_token(wstoken).await? {
+            SessionUpdateResult::Ok {
+                time_left: self.interval,
+            }
+        } else {
+            SessionUpdateResult::SessionDead
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const MOODLE_CONFIG: &str = r#"
+base_url: "https://moodle.example.edu/"
+rpm: 6000
+max_burst: 100
+user_agent: "moodle-session-ext tests"
+http:
+  fixtures:
+    mode: "replay"
+"#;
+
+    /// Replays the recorded moodle 4.1 interactions
+    fn recorded_moodle() -> Moodle {
+        let config = serde_yaml::from_str(&format!(
+            "{}    directory: \"{}/fixtures/moodle-4.1-en\"\n",
+            MOODLE_CONFIG,
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        Moodle::new(config).unwrap()
+    }
+
+    /// Replays the given responses to requests of `method` to `path` with `body`, in order
+    fn replaying(
+        method: &str,
+        path: &str,
+        body: &str,
+        responses: &[(u16, &str)],
+    ) -> (TempDir, Moodle) {
+        let dir = tempfile::tempdir().unwrap();
+        for (index, (status, response)) in responses.iter().enumerate() {
+            let interaction = serde_json::json!({
+                "request": { "method": method, "path": path, "body": body },
+                "response": { "status": status, "headers": [], "body": response },
+            });
+            std::fs::write(
+                dir.path().join(format!("{:04}.json", index)),
+                interaction.to_string(),
+            )
+            .unwrap();
+        }
+
+        let config = serde_yaml::from_str(&format!(
+            "{}    directory: {:?}\n",
+            MOODLE_CONFIG,
+            dir.path()
+        ))
+        .unwrap();
+        (dir, Moodle::new(config).unwrap())
+    }
+
+    fn browser_session() -> Credential {
+        Credential::BrowserSession {
+            moodle_session: "REDACTED".to_string(),
+            csrf_session: "REDACTED".to_string(),
+            cookies: CookieJar::new("REDACTED"),
+        }
+    }
+
+    #[tokio::test]
+    async fn ajax_touch_reports_the_time_moodle_has_left() {
+        let moodle = recorded_moodle();
+        let mut credential = browser_session();
+
+        assert!(matches!(
+            AjaxTouch.keep_alive(&moodle, &mut credential).await.unwrap(),
+            SessionUpdateResult::Ok { time_left } if time_left == Duration::from_secs(28740)
+        ));
+        assert!(matches!(
+            AjaxTouch
+                .keep_alive(&moodle, &mut credential)
+                .await
+                .unwrap(),
+            SessionUpdateResult::SessionDead
+        ));
+    }
+
+    #[tokio::test]
+    async fn page_fetch_assumes_the_session_timeout() {
+        let (_dir, moodle) = replaying("GET", "my/", "", &[(200, "<html></html>"), (303, "")]);
+        let page_fetch = PageFetch {
+            path: "/my/".to_string(),
+            session_timeout: Duration::from_secs(2 * 60 * 60),
+        };
+        let mut credential = browser_session();
+
+        assert!(matches!(
+            page_fetch.keep_alive(&moodle, &mut credential).await.unwrap(),
+            SessionUpdateResult::Ok { time_left } if time_left == page_fetch.session_timeout
+        ));
+        // redirected to the login page
+        assert!(matches!(
+            page_fetch
+                .keep_alive(&moodle, &mut credential)
+                .await
+                .unwrap(),
+            SessionUpdateResult::SessionDead
+        ));
+    }
+
+    #[tokio::test]
+    async fn web_service_ping_reports_the_interval() {
+        let (_dir, moodle) = replaying(
+            "POST",
+            "webservice/rest/server.php",
+            "wstoken=REDACTED&wsfunction=core_webservice_get_site_info&moodlewsrestformat=json",
+            &[
+                (200, r#"{"userid":42}"#),
+                (
+                    200,
+                    r#"{"exception":"moodle_exception","errorcode":"invalidtoken","message":"Invalid token"}"#,
+                ),
+            ],
+        );
+        let ping = WebServicePing {
+            interval: Duration::from_secs(6 * 60 * 60),
+        };
+        let mut credential = Credential::WebServiceToken("0123456789abcdef".to_string());
+
+        assert!(matches!(
+            ping.keep_alive(&moodle, &mut credential).await.unwrap(),
+            SessionUpdateResult::Ok { time_left } if time_left == ping.interval
+        ));
+        assert!(matches!(
+            ping.keep_alive(&moodle, &mut credential).await.unwrap(),
+            SessionUpdateResult::SessionDead
+        ));
+    }
+
+    #[tokio::test]
+    async fn strategies_refuse_the_other_kind_of_credential() {
+        let moodle = recorded_moodle();
+        let page_fetch = PageFetch {
+            path: "/my/".to_string(),
+            session_timeout: Duration::from_secs(60),
+        };
+        let ping = WebServicePing {
+            interval: Duration::from_secs(60),
+        };
+        let mut wstoken = Credential::WebServiceToken("0123456789abcdef".to_string());
+
+        assert!(AjaxTouch.keep_alive(&moodle, &mut wstoken).await.is_err());
+        assert!(page_fetch.keep_alive(&moodle, &mut wstoken).await.is_err());
+        assert!(ping
+            .keep_alive(&moodle, &mut browser_session())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,23 @@
+pub mod api;
+pub mod clock;
+pub mod config;
+pub mod cookies;
+pub mod db;
+pub mod events;
+pub mod fixtures;
+pub mod keepalive;
+pub mod model;
+pub mod moodle;
+pub mod notify;
+pub mod probe;
+pub mod reload;
+pub mod schedule;
+pub mod server;
+pub mod tls;
+pub mod updater;
+pub mod webhook;
+
+pub use config::Config;
+pub use db::Database;
+pub use model::Email;
+pub use moodle::Moodle;
@@ -1,11 +1,13 @@
-use crate::config::Config;
-use crate::db::Database;
-use crate::model::Email;
-use crate::moodle::Moodle;
-use crate::updater::update_loop;
-use anyhow::Context;
 use anyhow::Result;
+use anyhow::{bail, Context};
 use camino::Utf8PathBuf;
+use moodle_session_ext::clock::SystemClock;
+use moodle_session_ext::config::{Config, StartupProbe};
+use moodle_session_ext::events::Events;
+use moodle_session_ext::reload::Reloader;
+use moodle_session_ext::updater::update_loop;
+use moodle_session_ext::webhook::{Dispatcher, Webhooks};
+use moodle_session_ext::{keepalive, notify, probe, server, Database, Moodle};
 use opentelemetry::sdk::resource::{EnvResourceDetector, SdkProvidedResourceDetector};
 use opentelemetry::sdk::{trace as sdktrace, Resource};
 use opentelemetry_otlp::{HasExportConfig, WithExportConfig};
@@ -19,13 +21,6 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::Registry;
 
-pub mod config;
-pub mod db;
-pub mod model;
-pub mod moodle;
-pub mod server;
-pub mod updater;
-
 fn init_tracer() -> Result<sdktrace::Tracer> {
     let mut exporter = opentelemetry_otlp::new_exporter().tonic().with_env();
 
@@ -78,14 +73,40 @@ fn init_tracing() -> Result<()> {
     Ok(())
 }
 
+/// Logging for one-off commands: warnings on stderr, no exporter
+fn init_cli_tracing() {
+    let default = "moodle_session_ext=warn"
+        .parse()
+        .expect("hard-coded default directive should be valid");
+
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::builder()
+                .with_default_directive(default)
+                .from_env_lossy(),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let config_path = std::env::var("CONFIG")
         .map(Utf8PathBuf::from)
         .unwrap_or_else(|_| Utf8PathBuf::from_str("config.yml").unwrap());
+    let config_override_path = std::env::var("CONFIG_OVERRIDE").ok().map(Utf8PathBuf::from);
+
+    let config = Config::load(&config_path, config_override_path.as_deref())?;
 
-    let config = std::fs::read_to_string(config_path).context("Reading config file")?;
-    let config: Config = serde_yaml::from_str(&config).context("Parsing config file")?;
+    if std::env::args().nth(1).as_deref() == Some("probe") {
+        // a one-off check of the moodle instance, without starting anything. Warnings (e.g. about accepting
+        // invalid certificates) still go to stderr, next to the report
+        init_cli_tracing();
+        let moodle = Moodle::new(config.moodle.clone())?;
+        let report = probe::run(&moodle, &config.moodle).await;
+        print!("{}", report);
+        std::process::exit(if report.failed() { 1 } else { 0 });
+    }
 
     println!("config = {:#?}", config);
 
@@ -93,16 +114,42 @@ async fn main() -> Result<()> {
 
     info!("Starting...");
 
-    let db = Arc::new(Database::new(config.database)?);
-    let moodle = Arc::new(Moodle::new(config.moodle)?);
+    let db = Arc::new(Database::new(
+        config.database.clone(),
+        Arc::new(SystemClock),
+    )?);
+    let moodle = Arc::new(Moodle::new(config.moodle.clone())?);
+    if config.moodle.startup_probe != StartupProbe::Off {
+        let report = probe::run(&moodle, &config.moodle).await;
+        report.log();
+        if report.failed() && config.moodle.startup_probe == StartupProbe::Require {
+            bail!("The moodle instance failed the startup probe, refusing to start");
+        }
+    }
+    let browser_keep_alive = keepalive::from_config(&config.moodle.keep_alive);
+    let (notifier, mailer) = notify::new(config.notifications.clone(), db.clone())?;
+    let events = Arc::new(Events::new(Webhooks::new(
+        config.webhooks.as_ref(),
+        db.clone(),
+    )));
+    let dispatcher = Dispatcher::new(config.webhooks.clone(), db.clone())?;
+
+    let (reloader, updater_config, server_config) =
+        Reloader::new(config_path, config_override_path, config, moodle.clone());
 
     let update_fut = update_loop(
         db.clone(),
         moodle.clone(),
+        notifier,
+        events.clone(),
+        browser_keep_alive,
         db.subscribe_queue_updates()?,
-        config.updater,
+        updater_config,
     );
-    let server_fut = server::run(db.clone(), moodle.clone(), config.server);
+    let server_fut = server::run(db.clone(), moodle.clone(), events, server_config);
+    let reload_fut = reloader.run();
+    let mailer_fut = mailer.run();
+    let dispatcher_fut = dispatcher.run();
 
     select! {
         r = update_fut => {
@@ -113,6 +160,18 @@ async fn main() -> Result<()> {
             info!("Server loop finished");
             s.context("In server")
         }
+        r = reload_fut => {
+            info!("Config reloader finished");
+            r.context("In config reloader")
+        }
+        m = mailer_fut => {
+            info!("Mailer finished");
+            m.context("In mailer")
+        }
+        d = dispatcher_fut => {
+            info!("Webhook dispatcher finished");
+            d.context("In webhook dispatcher")
+        }
     }?;
 
     Ok(())
@@ -1,8 +1,11 @@
+use crate::cookies::CookieJar;
 use kv::{Error, Raw};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Debug, Formatter};
-use std::ops::Add;
 use std::time::{Duration, SystemTime};
+use utoipa::ToSchema;
 
 #[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
 pub struct TokenId([u8; 8]);
@@ -24,9 +27,7 @@ impl AsRef<[u8]> for TokenId {
 }
 impl<'a> kv::Key<'a> for TokenId {
     fn from_raw_key(x: &Raw) -> Result<Self, Error> {
-        let mut dst = Self([0; 8]);
-        dst.0[..8].clone_from_slice(&x.as_ref()[..8]);
-        Ok(dst)
+        Ok(Self(fixed_length_key(x)?))
     }
 }
 impl From<u64> for TokenId {
@@ -51,27 +52,42 @@ impl<'a> kv::Key<'a> for Email {
     }
 }
 
+/// Whether the value was written in the bincode format used before the switch to JSON
+pub fn is_legacy_value(r: &Raw) -> bool {
+    serde_json::from_slice::<serde::de::IgnoredAny>(r.as_ref()).is_err()
+}
+
+/// Values are stored as JSON, so that new fields can be added with `#[serde(default)]`. Bincode, which was used
+/// before, encodes fields by position, so adding `Token::next_refresh` (and every field after it) would have made
+/// existing databases unreadable. Values written before the switch are decoded as `$legacy` (which is `$name` itself,
+/// unless the type changed since then) and rewritten as JSON the next time they are stored
 macro_rules! impl_value {
     ($name:ident) => {
+        impl_value!($name, $name);
+    };
+    ($name:ident, $legacy:ident) => {
         impl kv::Value for $name {
             fn to_raw_value(&self) -> Result<Raw, Error> {
-                let bc = bincode::serialize(self)?;
-                Ok(bc.into())
+                let json = serde_json::to_vec(self).map_err(|e| Error::Message(e.to_string()))?;
+                Ok(json.into())
             }
 
             fn from_raw_value(r: Raw) -> Result<Self, Error> {
-                let de = bincode::deserialize(r.as_ref())?;
-                Ok(de)
+                if let Ok(de) = serde_json::from_slice(r.as_ref()) {
+                    return Ok(de);
+                }
+                let legacy: $legacy = bincode::deserialize(r.as_ref())?;
+                Ok(legacy.into())
             }
         }
     };
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Clone, Copy)]
 pub struct UpdateQueueKey([u8; 16]);
 
 impl UpdateQueueKey {
-    pub fn deadline(&self) -> SystemTime {
+    pub fn next_refresh(&self) -> SystemTime {
         let (t, _): (SystemTime, TokenId) = (*self).into();
         t
     }
@@ -96,68 +112,678 @@ impl AsRef<[u8]> for UpdateQueueKey {
 }
 impl<'a> kv::Key<'a> for UpdateQueueKey {
     fn from_raw_key(x: &Raw) -> Result<Self, Error> {
-        let mut dst = Self([0; 16]);
-        dst.0[..16].clone_from_slice(&x.as_ref()[..16]);
-        Ok(dst)
+        Ok(Self(time_key_from_raw(x)?))
     }
 }
 
-impl From<(SystemTime, TokenId)> for UpdateQueueKey {
-    fn from((t, id): (SystemTime, TokenId)) -> Self {
-        let t: u64 = t
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Weird time")
-            .as_millis()
-            .try_into()
-            .expect("SystemTime too far into the future");
+fn fixed_length_key<const N: usize>(x: &Raw) -> Result<[u8; N], Error> {
+    x.as_ref().try_into().map_err(|_| {
+        Error::Message(format!(
+            "Expected a key of {} bytes, got {}",
+            N,
+            x.as_ref().len()
+        ))
+    })
+}
+
+/// `None` if the time can't be represented on this platform
+fn time_from_millis(millis: u64) -> Option<SystemTime> {
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+}
 
-        let mut r = [0u8; 16];
-        r[..8].copy_from_slice(&t.to_be_bytes());
-        r[8..].copy_from_slice(&id.0);
+/// Keys ordered by time: big-endian unix millis followed by an 8-byte id.
+/// Times before the epoch or too far into the future can't be encoded
+fn encode_time_key(t: SystemTime, id: [u8; 8]) -> Result<[u8; 16], Error> {
+    let millis = t
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .and_then(|d| u64::try_from(d.as_millis()).ok())
+        .filter(|&millis| time_from_millis(millis).is_some())
+        .ok_or_else(|| Error::Message(format!("{:?} is out of range for a key", t)))?;
 
-        Self(r)
+    let mut r = [0u8; 16];
+    r[..8].copy_from_slice(&millis.to_be_bytes());
+    r[8..].copy_from_slice(&id);
+
+    Ok(r)
+}
+
+/// Only accepts keys that `decode_time_key` can decode
+fn time_key_from_raw(x: &Raw) -> Result<[u8; 16], Error> {
+    let key: [u8; 16] = fixed_length_key(x)?;
+    let (millis, _) = split_time_key(&key);
+    if time_from_millis(millis).is_none() {
+        return Err(Error::Message(format!(
+            "Time {}ms after the epoch is out of range",
+            millis
+        )));
     }
+
+    Ok(key)
 }
 
-impl From<UpdateQueueKey> for (SystemTime, TokenId) {
-    fn from(k: UpdateQueueKey) -> Self {
-        let mut time = [0u8; 8];
-        time.copy_from_slice(&k.0[..8]);
+fn split_time_key(k: &[u8; 16]) -> (u64, [u8; 8]) {
+    let (time, id) = k.split_at(8);
+    (
+        u64::from_be_bytes(time.try_into().unwrap()),
+        id.try_into().unwrap(),
+    )
+}
 
-        let time = u64::from_be_bytes(time);
-        let time = Duration::from_millis(time);
-        let time = SystemTime::UNIX_EPOCH.add(time);
+/// Keys are only created by `encode_time_key` and `time_key_from_raw`, so their time is always in range
+fn decode_time_key(k: &[u8; 16]) -> (SystemTime, [u8; 8]) {
+    let (millis, id) = split_time_key(k);
+    let time = time_from_millis(millis).expect("time keys are validated on creation");
 
-        let mut key = [0u8; 8];
-        key.copy_from_slice(&k.0[8..]);
+    (time, id)
+}
 
-        let key = TokenId(key);
+impl TryFrom<(SystemTime, TokenId)> for UpdateQueueKey {
+    type Error = Error;
 
-        (time, key)
+    fn try_from((t, id): (SystemTime, TokenId)) -> Result<Self, Error> {
+        Ok(Self(encode_time_key(t, id.0)?))
     }
 }
 
+impl From<UpdateQueueKey> for (SystemTime, TokenId) {
+    fn from(k: UpdateQueueKey) -> Self {
+        let (time, key) = decode_time_key(&k.0);
+        (time, TokenId(key))
+    }
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// What a user can be notified about
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NotificationKind {
+    SessionDead,
+    RefreshFailing,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct User {
     pub email: Email,
     pub tokens: Vec<TokenId>,
+    #[serde(default)]
+    pub notifications_opted_out: bool,
+    /// When the user was last notified about each kind of event, used to not spam them
+    #[serde(default)]
+    pub last_notified: HashMap<NotificationKind, SystemTime>,
 }
-impl_value!(User);
+impl_value!(User, LegacyUser);
 
-#[derive(Serialize, Deserialize, Debug)]
+impl User {
+    pub fn new(email: Email) -> Self {
+        Self {
+            email,
+            tokens: Vec::new(),
+            notifications_opted_out: false,
+            last_notified: HashMap::new(),
+        }
+    }
+}
+
+/// `User` as it was stored before notifications were introduced
+#[derive(Deserialize)]
+pub struct LegacyUser {
+    email: Email,
+    tokens: Vec<TokenId>,
+}
+
+impl From<LegacyUser> for User {
+    fn from(u: LegacyUser) -> Self {
+        Self {
+            tokens: u.tokens,
+            ..User::new(u.email)
+        }
+    }
+}
+
+/// How many refresh outcomes to keep in `Token::recent_outcomes`
+const RECENT_OUTCOMES: usize = 10;
+/// How many rotated-away session cookies to keep in `Token::previous_session_digests`
+const PREVIOUS_SESSIONS: usize = 5;
+
+/// Stored in place of a session cookie or web service token, where only finding the token by it is needed
+pub fn session_digest(moodle_session: &str) -> String {
+    hex::encode(Sha256::digest(moodle_session.as_bytes()))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RefreshResult {
+    Extended {
+        time_left: Duration,
+    },
+    Failed {
+        error: String,
+    },
+    /// Moodle didn't respond in time. The refresh is retried shortly, so this doesn't count as a failure
+    TimedOut {
+        error: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RefreshOutcome {
+    #[serde(with = "serde_millis")]
+    pub at: SystemTime,
+    pub result: RefreshResult,
+}
+
+/// What the user handed over to keep their access alive
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialKind {
+    /// A browser session cookie, kept alive with the AJAX session endpoints
+    #[default]
+    BrowserSession,
+    /// A web service token (e.g. of the mobile app), kept alive with web service calls
+    WebServiceToken,
+}
+
+/// A credential along with everything needed to use it with moodle
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credential {
+    BrowserSession {
+        moodle_session: String,
+        csrf_session: String,
+        cookies: CookieJar,
+    },
+    WebServiceToken(String),
+}
+
+impl Credential {
+    pub fn kind(&self) -> CredentialKind {
+        match self {
+            Credential::BrowserSession { .. } => CredentialKind::BrowserSession,
+            Credential::WebServiceToken(_) => CredentialKind::WebServiceToken,
+        }
+    }
+
+    /// The value tokens are looked up by
+    pub fn secret(&self) -> &str {
+        match self {
+            Credential::BrowserSession { moodle_session, .. } => moodle_session,
+            Credential::WebServiceToken(wstoken) => wstoken,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Token {
     pub owner: Email,
+    #[serde(default)]
+    pub credential: CredentialKind,
+    /// The session cookie, or the web service token if `credential` is `WebServiceToken`. If the web service token is
+    /// sealed in `sealed_wstoken`, this is only its `session_digest`
     pub moodle_session: String,
+    /// Empty for web service tokens
     pub csrf_session: String,
+    /// When the session expires if not refreshed
     #[serde(with = "serde_millis")]
     pub deadline: SystemTime,
     #[serde(with = "serde_millis")]
     pub added: SystemTime,
+    /// When the session should be refreshed next. This is what the update queue is ordered by
+    #[serde(with = "serde_millis")]
+    pub next_refresh: SystemTime,
+    /// When the session was last extended successfully
+    #[serde(default, with = "serde_millis")]
+    pub last_extended: Option<SystemTime>,
+    #[serde(default)]
+    pub extension_count: u64,
+    /// Remaining session time reported by moodle on the last successful refresh
+    #[serde(default)]
+    pub last_time_remaining: Option<Duration>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Number of failed refreshes since the last successful one
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub recent_outcomes: VecDeque<RefreshOutcome>,
+    /// The session is not extended past this time, if set
+    #[serde(default, with = "serde_millis")]
+    pub extend_until: Option<SystemTime>,
+    /// Other cookies to send along with the session cookie, sealed with `CookieCipher`
+    #[serde(default)]
+    pub cookies: Option<String>,
+    /// `session_digest`s of the values the session cookie had before moodle rotated it, so that the user can still
+    /// find the token by them
+    #[serde(default)]
+    pub previous_session_digests: VecDeque<String>,
+    /// When moodle last issued a new session cookie
+    #[serde(default, with = "serde_millis")]
+    pub rotated_at: Option<SystemTime>,
+    /// Number of refreshes that started after the deadline had passed
+    #[serde(default)]
+    pub missed_deadlines: u32,
+    /// The web service token sealed with `CookieCipher`, if `database.encryption_key` is set
+    #[serde(default)]
+    pub sealed_wstoken: Option<String>,
+}
+impl_value!(Token, LegacyToken);
+
+impl Token {
+    /// Creates a token that was never refreshed and should be refreshed right away
+    pub fn new(
+        owner: Email,
+        moodle_session: String,
+        csrf_session: String,
+        added: SystemTime,
+    ) -> Self {
+        Self {
+            owner,
+            credential: CredentialKind::BrowserSession,
+            moodle_session,
+            csrf_session,
+            // a lot of time ago...
+            deadline: SystemTime::UNIX_EPOCH,
+            added,
+            next_refresh: SystemTime::UNIX_EPOCH,
+            last_extended: None,
+            extension_count: 0,
+            last_time_remaining: None,
+            last_error: None,
+            consecutive_failures: 0,
+            recent_outcomes: VecDeque::new(),
+            extend_until: None,
+            cookies: None,
+            previous_session_digests: VecDeque::new(),
+            rotated_at: None,
+            missed_deadlines: 0,
+            sealed_wstoken: None,
+        }
+    }
+
+    /// Whether the session cookie has (or had before a rotation) this value
+    pub fn has_session(&self, moodle_session: &str) -> bool {
+        let digest = session_digest(moodle_session);
+        let current = match self.sealed_wstoken {
+            Some(_) => self.moodle_session == digest,
+            None => self.moodle_session == moodle_session,
+        };
+        current || self.previous_session_digests.contains(&digest)
+    }
+
+    /// The digests of the current and previous session values, which the token can be found by
+    pub fn session_digests(&self) -> Vec<String> {
+        let current = match self.sealed_wstoken {
+            Some(_) => self.moodle_session.clone(),
+            None => session_digest(&self.moodle_session),
+        };
+        std::iter::once(current)
+            .chain(self.previous_session_digests.iter().cloned())
+            .collect()
+    }
+
+    /// Switches to the session cookie moodle issued instead of the current one. Returns the digest of the previous
+    /// value that is not kept anymore, if any
+    pub fn rotate_session(&mut self, moodle_session: String) -> Option<String> {
+        let dropped = if self.previous_session_digests.len() >= PREVIOUS_SESSIONS {
+            self.previous_session_digests.pop_front()
+        } else {
+            None
+        };
+        let previous = std::mem::replace(&mut self.moodle_session, moodle_session);
+        self.previous_session_digests
+            .push_back(session_digest(&previous));
+        dropped
+    }
+
+    pub fn record_outcome(&mut self, at: SystemTime, result: RefreshResult) {
+        match &result {
+            RefreshResult::Extended { time_left } => {
+                self.last_extended = Some(at);
+                self.extension_count += 1;
+                self.last_time_remaining = Some(*time_left);
+                self.consecutive_failures = 0;
+            }
+            RefreshResult::Failed { error } => {
+                self.last_error = Some(error.clone());
+                self.consecutive_failures += 1;
+            }
+            RefreshResult::TimedOut { error } => {
+                self.last_error = Some(error.clone());
+            }
+        }
+
+        if self.recent_outcomes.len() >= RECENT_OUTCOMES {
+            self.recent_outcomes.pop_front();
+        }
+        self.recent_outcomes
+            .push_back(RefreshOutcome { at, result });
+    }
+}
+
+/// `Token` as it was stored before scheduling was decoupled from the deadline
+#[derive(Deserialize)]
+pub struct LegacyToken {
+    owner: Email,
+    moodle_session: String,
+    csrf_session: String,
+    #[serde(with = "serde_millis")]
+    deadline: SystemTime,
+    #[serde(with = "serde_millis")]
+    added: SystemTime,
+}
+
+impl From<LegacyToken> for Token {
+    fn from(t: LegacyToken) -> Self {
+        Self {
+            deadline: t.deadline,
+            // legacy update queue is keyed by the deadline
+            next_refresh: t.deadline,
+            ..Token::new(t.owner, t.moodle_session, t.csrf_session, t.added)
+        }
+    }
 }
-impl_value!(Token);
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateQueueItem {
     pub token: TokenId,
 }
 impl_value!(UpdateQueueItem);
+
+/// Key of the session index: the `session_digest` of a current or previous session value of a token
+#[derive(Clone, Debug)]
+pub struct SessionKey(pub String);
+
+impl AsRef<[u8]> for SessionKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+impl<'a> kv::Key<'a> for SessionKey {
+    fn from_raw_key(r: &'a Raw) -> Result<Self, Error> {
+        Ok(Self(std::str::from_utf8(r.as_ref())?.to_string()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionIndexItem {
+    pub token: TokenId,
+}
+impl_value!(SessionIndexItem);
+
+/// Webhook deliveries are ordered by the time of the next attempt
+#[derive(Clone, Copy)]
+pub struct WebhookDeliveryKey([u8; 16]);
+
+impl WebhookDeliveryKey {
+    pub fn next_attempt(&self) -> SystemTime {
+        decode_time_key(&self.0).0
+    }
+    pub fn delivery_id(&self) -> u64 {
+        u64::from_be_bytes(decode_time_key(&self.0).1)
+    }
+}
+
+impl Debug for WebhookDeliveryKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("WebhookDeliveryKey")
+            .field(&self.next_attempt())
+            .field(&self.delivery_id())
+            .finish()
+    }
+}
+
+impl AsRef<[u8]> for WebhookDeliveryKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+impl<'a> kv::Key<'a> for WebhookDeliveryKey {
+    fn from_raw_key(x: &Raw) -> Result<Self, Error> {
+        Ok(Self(time_key_from_raw(x)?))
+    }
+}
+
+impl TryFrom<(SystemTime, u64)> for WebhookDeliveryKey {
+    type Error = Error;
+
+    fn try_from((t, id): (SystemTime, u64)) -> Result<Self, Error> {
+        Ok(Self(encode_time_key(t, id.to_be_bytes())?))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebhookDelivery {
+    pub url: String,
+    /// JSON body of the request. Signed anew on every attempt
+    pub payload: String,
+    pub attempts: u32,
+    #[serde(with = "serde_millis")]
+    pub created: SystemTime,
+    pub last_error: Option<String>,
+}
+impl_value!(WebhookDelivery);
+
+/// Key of the only `Stats` record
+pub struct StatsKey;
+
+impl AsRef<[u8]> for StatsKey {
+    fn as_ref(&self) -> &[u8] {
+        b"stats"
+    }
+}
+impl<'a> kv::Key<'a> for StatsKey {
+    fn from_raw_key(_: &Raw) -> Result<Self, Error> {
+        Ok(Self)
+    }
+}
+
+/// Server-wide counters, kept across restarts
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Stats {
+    /// Refreshes that started after the deadline of their token
+    #[serde(default)]
+    pub missed_deadlines: u64,
+}
+impl_value!(Stats);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv::{Key, Value};
+    use proptest::prelude::*;
+
+    fn time(millis: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    fn queue_key(millis: u64, id: u64) -> UpdateQueueKey {
+        UpdateQueueKey::try_from((time(millis), TokenId::from(id))).unwrap()
+    }
+
+    /// `Token` as bincode encoded it before the switch to JSON
+    #[derive(Serialize)]
+    struct BincodeToken {
+        owner: Email,
+        moodle_session: String,
+        csrf_session: String,
+        #[serde(with = "serde_millis")]
+        deadline: SystemTime,
+        #[serde(with = "serde_millis")]
+        added: SystemTime,
+    }
+
+    #[derive(Serialize)]
+    struct BincodeUser {
+        email: Email,
+        tokens: Vec<TokenId>,
+    }
+
+    #[test]
+    fn legacy_tokens_are_queued_by_their_deadline() {
+        let raw = Raw::from(
+            bincode::serialize(&BincodeToken {
+                owner: Email("student@example.edu".to_string()),
+                moodle_session: "s3ss10n".to_string(),
+                csrf_session: "sesskey".to_string(),
+                deadline: time(1_700_000_000_000),
+                added: time(1_600_000_000_000),
+            })
+            .unwrap(),
+        );
+        assert!(is_legacy_value(&raw));
+
+        let token = Token::from_raw_value(raw).unwrap();
+        assert_eq!(token.owner.0, "student@example.edu");
+        assert_eq!(token.moodle_session, "s3ss10n");
+        assert_eq!(token.csrf_session, "sesskey");
+        assert_eq!(token.deadline, time(1_700_000_000_000));
+        assert_eq!(token.next_refresh, time(1_700_000_000_000));
+        assert_eq!(token.added, time(1_600_000_000_000));
+
+        // once stored again, it's JSON
+        let raw = token.to_raw_value().unwrap();
+        assert!(!is_legacy_value(&raw));
+        assert_eq!(
+            Token::from_raw_value(raw).unwrap().next_refresh,
+            time(1_700_000_000_000)
+        );
+    }
+
+    #[test]
+    fn legacy_users_keep_their_tokens() {
+        let raw = Raw::from(
+            bincode::serialize(&BincodeUser {
+                email: Email("student@example.edu".to_string()),
+                tokens: vec![TokenId::from(1), TokenId::from(7)],
+            })
+            .unwrap(),
+        );
+        assert!(is_legacy_value(&raw));
+
+        let user = User::from_raw_value(raw).unwrap();
+        assert_eq!(user.email.0, "student@example.edu");
+        assert_eq!(user.tokens, vec![TokenId::from(1), TokenId::from(7)]);
+        assert!(!user.notifications_opted_out);
+    }
+
+    #[test]
+    fn times_are_stored_as_milliseconds() {
+        let mut token = Token::new(
+            Email("student@example.edu".to_string()),
+            "s3ss10n".to_string(),
+            String::new(),
+            time(1_000),
+        );
+        token.extend_until = Some(time(5_000));
+        token.record_outcome(
+            time(2_000),
+            RefreshResult::Extended {
+                time_left: Duration::from_secs(60),
+            },
+        );
+
+        let json: serde_json::Value = serde_json::to_value(&token).unwrap();
+        assert_eq!(json["last_extended"], 2_000);
+        assert_eq!(json["extend_until"], 5_000);
+        assert_eq!(json["recent_outcomes"][0]["at"], 2_000);
+
+        let token: Token = serde_json::from_value(json).unwrap();
+        assert_eq!(token.last_extended, Some(time(2_000)));
+        assert_eq!(token.extend_until, Some(time(5_000)));
+        assert_eq!(token.recent_outcomes[0].at, time(2_000));
+    }
+
+    #[test]
+    fn sealed_web_service_tokens_are_found_by_their_digest() {
+        let mut token = Token::new(
+            Email("student@example.edu".to_string()),
+            session_digest("0123456789abcdef"),
+            String::new(),
+            time(0),
+        );
+        token.credential = CredentialKind::WebServiceToken;
+        token.sealed_wstoken = Some("sealed".to_string());
+
+        assert!(token.has_session("0123456789abcdef"));
+        assert!(!token.has_session(&session_digest("0123456789abcdef")));
+    }
+
+    proptest! {
+        #[test]
+        fn token_id_round_trips(id: u64) {
+            let token_id = TokenId::from(id);
+            prop_assert_eq!(u64::from(token_id), id);
+
+            let raw = Raw::from(token_id.as_ref());
+            prop_assert_eq!(TokenId::from_raw_key(&raw).unwrap(), token_id);
+        }
+
+        #[test]
+        fn update_queue_key_round_trips(millis: u64, id: u64) {
+            let key = queue_key(millis, id);
+            prop_assert_eq!(key.next_refresh(), time(millis));
+            prop_assert_eq!(key.token_id(), TokenId::from(id));
+
+            let raw = Raw::from(key.as_ref());
+            let decoded = UpdateQueueKey::from_raw_key(&raw).unwrap();
+            prop_assert_eq!(decoded.as_ref(), key.as_ref());
+        }
+
+        #[test]
+        fn webhook_delivery_key_round_trips(millis: u64, id: u64) {
+            let key = WebhookDeliveryKey::try_from((time(millis), id)).unwrap();
+            prop_assert_eq!(key.next_attempt(), time(millis));
+            prop_assert_eq!(key.delivery_id(), id);
+
+            let raw = Raw::from(key.as_ref());
+            let decoded = WebhookDeliveryKey::from_raw_key(&raw).unwrap();
+            prop_assert_eq!(decoded.as_ref(), key.as_ref());
+        }
+
+        #[test]
+        fn key_order_matches_time_then_id(a: (u64, u64), b: (u64, u64)) {
+            let (key_a, key_b) = (queue_key(a.0, a.1), queue_key(b.0, b.1));
+            prop_assert_eq!(key_a.as_ref().cmp(key_b.as_ref()), a.cmp(&b));
+        }
+
+        #[test]
+        fn sub_millisecond_precision_is_dropped(millis in 0..u64::MAX / 2, nanos in 0..1_000_000u32) {
+            let t = time(millis) + Duration::from_nanos(nanos.into());
+            let key = UpdateQueueKey::try_from((t, TokenId::from(0))).unwrap();
+            prop_assert_eq!(key.next_refresh(), time(millis));
+        }
+
+        #[test]
+        fn times_before_the_epoch_are_rejected(millis in 1..u64::MAX / 2, id: u64) {
+            let t = SystemTime::UNIX_EPOCH - Duration::from_millis(millis);
+            prop_assert!(UpdateQueueKey::try_from((t, TokenId::from(id))).is_err());
+            prop_assert!(WebhookDeliveryKey::try_from((t, id)).is_err());
+        }
+
+        #[test]
+        fn raw_keys_of_any_length_dont_panic(bytes in prop::collection::vec(any::<u8>(), 0..40)) {
+            let raw = Raw::from(bytes.as_slice());
+
+            match TokenId::from_raw_key(&raw) {
+                Ok(id) => prop_assert_eq!(id.as_ref(), bytes.as_slice()),
+                Err(_) => prop_assert_ne!(bytes.len(), 8),
+            }
+            match UpdateQueueKey::from_raw_key(&raw) {
+                Ok(key) => {
+                    prop_assert_eq!(key.as_ref(), bytes.as_slice());
+                    let _ = format!("{:?}", key);
+                }
+                Err(_) => prop_assert_ne!(bytes.len(), 16),
+            }
+            match WebhookDeliveryKey::from_raw_key(&raw) {
+                Ok(key) => {
+                    prop_assert_eq!(key.as_ref(), bytes.as_slice());
+                    let _ = format!("{:?}", key);
+                }
+                Err(_) => prop_assert_ne!(bytes.len(), 16),
+            }
+        }
+    }
+}
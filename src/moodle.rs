@@ -1,3 +1,5 @@
+use crate::cookies::CookieJar;
+use crate::fixtures::Fixtures;
 use crate::{config, Email};
 use anyhow::{anyhow, Context, Result};
 use email_address::EmailAddress;
@@ -6,21 +8,25 @@ use governor::state::{InMemoryState, NotKeyed};
 use governor::{Quota, RateLimiter};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use reqwest::header::{HeaderValue, COOKIE, LOCATION};
+use reqwest::header::{HeaderValue, COOKIE, LOCATION, SET_COOKIE, USER_AGENT};
 use reqwest::redirect::Policy;
-use reqwest::{Request, Response, Url};
+use reqwest::{Certificate, Identity, Request, Response, StatusCode, Url};
 use reqwest_tracing::{
     default_on_request_end, reqwest_otel_span, ReqwestOtelSpanBackend, TracingMiddleware,
 };
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::num::NonZeroU32;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use task_local_extensions::Extensions;
-use tracing::{info, instrument};
+use tokio::time::timeout;
+use tracing::{info, instrument, warn};
 
+/// The label depends on the language of the user, so the first `mailto:` link among the user details is taken
 static EMAIL_EXTRACT_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#"<dt>Email address</dt><dd><a href="([^"]+)">"#).unwrap());
+    Lazy::new(|| Regex::new(r#"<dt>[^<]*</dt>\s*<dd><a href="(mailto:[^"]+)">"#).unwrap());
 static SESSION_EXTRACT_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r#""sesskey":"([^"]+)""#).unwrap());
 
@@ -55,10 +61,16 @@ pub enum SessionUpdateResult {
     Ok { time_left: Duration },
 }
 
+type DirectRateLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
 pub struct Moodle {
     reqwest: reqwest_middleware::ClientWithMiddleware,
     base_url: Url,
-    rate_limiter: RateLimiter<NotKeyed, InMemoryState, DefaultClock>,
+    session_cookie: String,
+    timeouts: config::Timeouts,
+    // these two can be changed when the config is reloaded
+    rate_limiter: RwLock<Arc<DirectRateLimiter>>,
+    user_agent: RwLock<HeaderValue>,
 }
 
 #[derive(Serialize)]
@@ -83,6 +95,55 @@ impl Display for AjaxError {
 
 impl std::error::Error for AjaxError {}
 
+/// Moodle (or a proxy in front of it) refused the request because too many requests were made
+#[derive(Debug)]
+pub struct RateLimited;
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Moodle responded with 429 Too Many Requests")
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Too many requests to moodle are waiting for the rate limit, so an interactive one was not queued
+#[derive(Debug)]
+pub struct Busy;
+
+impl Display for Busy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Too many requests to moodle are queued")
+    }
+}
+
+impl std::error::Error for Busy {}
+
+/// Moodle didn't respond in time. Retrying later may succeed
+#[derive(Debug)]
+pub struct TimedOut;
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Moodle did not respond in time")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Whether the error is a timeout, either of a single request or of a whole operation
+pub fn is_timed_out(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.is::<TimedOut>()
+            || e.downcast_ref::<reqwest::Error>()
+                .is_some_and(reqwest::Error::is_timeout)
+            || matches!(
+                e.downcast_ref::<reqwest_middleware::Error>(),
+                Some(reqwest_middleware::Error::Reqwest(e)) if e.is_timeout()
+            )
+    })
+}
+
 #[derive(Debug)]
 enum AjaxResult<T: Deserialize<'static>> {
     Ok(T),
@@ -90,6 +151,19 @@ enum AjaxResult<T: Deserialize<'static>> {
     Error(AjaxError),
 }
 
+#[derive(Deserialize)]
+struct SiteInfo {
+    userid: u64,
+}
+
+#[derive(Deserialize)]
+struct WebServiceUser {
+    email: Option<String>,
+}
+
+/// Web service error codes meaning that the token is not accepted anymore
+const INVALID_TOKEN_ERRORS: &[&str] = &["invalidtoken", "invalidtimedtoken"];
+
 #[derive(Deserialize)]
 #[allow(dead_code)]
 struct SessionTime {
@@ -97,45 +171,161 @@ struct SessionTime {
     timeremaining: u64,
 }
 
+/// Moodle can be installed under a sub-path, which is only kept by `Url::join` if it ends with a slash
+fn with_trailing_slash(mut base_url: Url) -> Url {
+    if !base_url.path().ends_with('/') {
+        base_url.set_path(&format!("{}/", base_url.path()));
+    }
+    base_url
+}
+
+fn make_rate_limiter(rpm: u32, max_burst: u32) -> DirectRateLimiter {
+    let period = Duration::from_millis(1000 * 60 / rpm as u64);
+
+    let quota = Quota::with_period(period)
+        .unwrap()
+        .allow_burst(NonZeroU32::new(max_burst).unwrap());
+
+    governor::RateLimiter::direct(quota)
+}
+
+fn make_client(config: &config::HttpClient) -> Result<reqwest::Client> {
+    let mut builder = reqwest::ClientBuilder::new()
+        .redirect(Policy::none())
+        .connect_timeout(config.timeouts.connect)
+        .timeout(config.timeouts.request);
+
+    if let Some(proxy) = &config.proxy {
+        let mut p = reqwest::Proxy::all(proxy.url.clone()).context("Setting up the proxy")?;
+        if let Some(username) = &proxy.username {
+            let password = proxy
+                .password
+                .as_ref()
+                .map(|p| p.expose())
+                .unwrap_or_default();
+            p = p.basic_auth(username, password);
+        }
+        info!(
+            "Connecting to moodle through a {} proxy at {}",
+            proxy.url.scheme(),
+            proxy.url.host_str().unwrap_or_default()
+        );
+        builder = builder.proxy(p);
+    }
+
+    for path in &config.root_certificates {
+        let pem = std::fs::read(path).with_context(|| format!("Reading {}", path))?;
+        let certificate = Certificate::from_pem(&pem)
+            .with_context(|| format!("Parsing the certificate in {}", path))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    if let Some(client_certificate) = &config.client_certificate {
+        let mut pem = std::fs::read(&client_certificate.certificate)
+            .with_context(|| format!("Reading {}", client_certificate.certificate))?;
+        pem.push(b'\n');
+        pem.extend(
+            std::fs::read(&client_certificate.key)
+                .with_context(|| format!("Reading {}", client_certificate.key))?,
+        );
+        builder =
+            builder.identity(Identity::from_pem(&pem).context("Parsing the client certificate")?);
+    }
+
+    if config.accept_invalid_certificates {
+        warn!("!!! Accepting invalid certificates of moodle. This is insecure and only meant for development !!!");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder.build()?)
+}
+
 impl Moodle {
     pub fn new(config: config::Moodle) -> Result<Self> {
-        let period = Duration::from_millis(1000 * 60 / config.rpm as u64);
+        let rate_limiter = make_rate_limiter(config.rpm, config.max_burst);
 
-        let quota = Quota::with_period(period)
-            .unwrap()
-            .allow_burst(NonZeroU32::new(config.max_burst).unwrap());
+        let base_url = with_trailing_slash(config.base_url);
 
-        let rate_limiter = governor::RateLimiter::direct(quota);
+        let mut reqwest = reqwest_middleware::ClientBuilder::new(make_client(&config.http)?)
+            .with(TracingMiddleware::<TimeTrace>::new());
+        if let Some(fixtures) = &config.http.fixtures {
+            reqwest = reqwest.with(Fixtures::new(fixtures, base_url.clone())?);
+        }
 
         Ok(Self {
-            reqwest: reqwest_middleware::ClientBuilder::new(
-                reqwest::ClientBuilder::new()
-                    .user_agent(config.user_agent)
-                    .redirect(Policy::none())
-                    .build()?,
-            )
-            .with(TracingMiddleware::<TimeTrace>::new())
-            .build(),
-            base_url: config.base_url,
-            rate_limiter,
+            reqwest: reqwest.build(),
+            base_url,
+            session_cookie: config.session_cookie,
+            timeouts: config.http.timeouts,
+            rate_limiter: RwLock::new(Arc::new(rate_limiter)),
+            user_agent: RwLock::new(HeaderValue::from_str(&config.user_agent)?),
         })
     }
 
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Name of the session cookie, `MoodleSession` followed by `$CFG->sessioncookie`
+    pub fn session_cookie(&self) -> &str {
+        &self.session_cookie
+    }
+
+    /// Replaces the rate limiter with a new one. Note that this resets the state of the limiter
+    pub fn set_rate_limit(&self, rpm: u32, max_burst: u32) {
+        *self.rate_limiter.write().unwrap() = Arc::new(make_rate_limiter(rpm, max_burst));
+    }
+
+    pub fn set_user_agent(&self, user_agent: &str) -> Result<()> {
+        *self.user_agent.write().unwrap() = HeaderValue::from_str(user_agent)?;
+        Ok(())
+    }
+
+    /// Interactive requests pass `max_wait`, so that the user gets an error instead of waiting for long
+    async fn wait_for_rate_limit(&self, max_wait: Option<Duration>) -> Result<()> {
+        let rate_limiter = self.rate_limiter.read().unwrap().clone();
+        match max_wait {
+            None => rate_limiter.until_ready().await,
+            Some(max_wait) => timeout(max_wait, rate_limiter.until_ready())
+                .await
+                .map_err(|_| Busy)?,
+        }
+        Ok(())
+    }
+
+    /// Limits the time a whole operation (possibly consisting of several requests) can take
+    pub async fn with_timeout<T>(&self, operation: impl Future<Output = Result<T>>) -> Result<T> {
+        timeout(self.timeouts.operation, operation)
+            .await
+            .map_err(|_| TimedOut)?
+    }
+
+    fn user_agent(&self) -> HeaderValue {
+        self.user_agent.read().unwrap().clone()
+    }
+
+    /// Cookies set by moodle are merged into the jar
     #[instrument(skip_all)]
-    pub async fn check_session(&self, moodle_session: &str) -> Result<SessionProbeResult> {
-        self.rate_limiter.until_ready().await;
+    pub async fn check_session(&self, cookies: &mut CookieJar) -> Result<SessionProbeResult> {
+        self.wait_for_rate_limit(Some(self.timeouts.rate_limit_wait))
+            .await?;
 
-        let url = self.base_url.join("/user/profile.php")?;
+        let url = self.base_url.join("user/profile.php")?;
 
         let resp = self
             .reqwest
             .get(url)
-            .header(
-                COOKIE,
-                HeaderValue::from_str(&format!("MoodleSession={}", moodle_session))?,
-            )
+            .header(USER_AGENT, self.user_agent())
+            .header(COOKIE, cookies.header_value(&self.session_cookie)?)
             .send()
             .await?;
+        cookies.merge_set_cookies(
+            &self.session_cookie,
+            resp.headers().get_all(SET_COOKIE).iter(),
+        );
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(RateLimited.into());
+        }
         if resp.status().is_redirection() {
             info!(
                 "Moodle redirected using status {} to {:?}; sessions is likely invalid",
@@ -181,27 +371,62 @@ impl Moodle {
         })
     }
 
+    /// Fetches a page with the session, so that moodle sees a page view. Returns `false` if moodle redirected
+    /// away from it, which means that the session is dead
+    #[instrument(skip(self, cookies))]
+    pub async fn fetch_page(&self, cookies: &mut CookieJar, path: &str) -> Result<bool> {
+        self.wait_for_rate_limit(None).await?;
+
+        let url = self.base_url.join(path.trim_start_matches('/'))?;
+
+        let resp = self
+            .reqwest
+            .get(url)
+            .header(USER_AGENT, self.user_agent())
+            .header(COOKIE, cookies.header_value(&self.session_cookie)?)
+            .send()
+            .await?;
+        cookies.merge_set_cookies(
+            &self.session_cookie,
+            resp.headers().get_all(SET_COOKIE).iter(),
+        );
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(RateLimited.into());
+        }
+        if resp.status().is_redirection() {
+            info!(
+                "Moodle redirected using status {} to {:?}; session is likely dead",
+                resp.status(),
+                resp.headers().get(LOCATION)
+            );
+            return Ok(false);
+        }
+        if !resp.status().is_success() {
+            return Err(anyhow!("Moodle responded with status {}", resp.status()));
+        }
+
+        Ok(true)
+    }
+
     #[instrument(skip_all, fields(name = format!("ajax {}", method_name)))]
     async fn ajax<T: Serialize, R: for<'de> Deserialize<'de>>(
         &self,
-        moodle_session: &str,
+        cookies: &mut CookieJar,
         csrf_session: &str,
         method_name: &str,
         args: T,
     ) -> Result<AjaxResult<R>> {
-        self.rate_limiter.until_ready().await;
+        self.wait_for_rate_limit(None).await?;
 
         let url = self
             .base_url
-            .join(&format!("/lib/ajax/service.php?sesskey={}", csrf_session))?;
+            .join(&format!("lib/ajax/service.php?sesskey={}", csrf_session))?;
 
         let resp = self
             .reqwest
             .post(url)
-            .header(
-                COOKIE,
-                HeaderValue::from_str(&format!("MoodleSession={}", moodle_session))?,
-            )
+            .header(USER_AGENT, self.user_agent())
+            .header(COOKIE, cookies.header_value(&self.session_cookie)?)
             .json(&[AjaxPayload::<T> {
                 index: 0,
                 methodname: method_name.to_string(),
@@ -209,6 +434,13 @@ impl Moodle {
             }])
             .send()
             .await?;
+        cookies.merge_set_cookies(
+            &self.session_cookie,
+            resp.headers().get_all(SET_COOKIE).iter(),
+        );
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(RateLimited.into());
+        }
 
         let resp = resp.text().await.context("Reading body as string")?;
 
@@ -261,11 +493,213 @@ impl Moodle {
         ))
     }
 
-    async fn touch_session(&self, moodle_session: &str, csrf_session: &str) -> Result<bool> {
+    #[instrument(skip_all, fields(name = format!("web service {}", function)))]
+    async fn web_service<R: for<'de> Deserialize<'de>>(
+        &self,
+        wstoken: &str,
+        function: &str,
+        args: &[(&str, String)],
+        max_wait: Option<Duration>,
+    ) -> Result<AjaxResult<R>> {
+        self.wait_for_rate_limit(max_wait).await?;
+
+        let url = self.base_url.join("webservice/rest/server.php")?;
+
+        let mut form = vec![
+            ("wstoken", wstoken.to_string()),
+            ("wsfunction", function.to_string()),
+            ("moodlewsrestformat", "json".to_string()),
+        ];
+        form.extend(args.iter().map(|(k, v)| (*k, v.clone())));
+
+        let resp = self
+            .reqwest
+            .post(url)
+            .header(USER_AGENT, self.user_agent())
+            .form(&form)
+            .send()
+            .await?;
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(RateLimited.into());
+        }
+
+        let resp = resp.text().await.context("Reading body as string")?;
+        let resp: serde_json::Value =
+            serde_json::from_str(&resp).context("Parsing body as untyped JSON")?;
+
+        if let Some(exception) = resp.as_object().filter(|o| o.contains_key("exception")) {
+            let errorcode = exception
+                .get("errorcode")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow!("Missing \"errorcode\" field in exception or wrong type"))?;
+            let message = exception
+                .get("message")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default();
+
+            if INVALID_TOKEN_ERRORS.contains(&errorcode) {
+                return Ok(AjaxResult::SessionDead);
+            }
+
+            return Ok(AjaxResult::Error(AjaxError {
+                text: message.to_string(),
+                code: errorcode.to_string(),
+            }));
+        }
+
+        Ok(AjaxResult::Ok(
+            serde_json::from_value(resp).context("Parsing response as typed result")?,
+        ))
+    }
+
+    async fn site_info(
+        &self,
+        wstoken: &str,
+        max_wait: Option<Duration>,
+    ) -> Result<Option<SiteInfo>> {
+        Ok(
+            match self
+                .web_service::<SiteInfo>(wstoken, "core_webservice_get_site_info", &[], max_wait)
+                .await
+                .context("site_info")?
+            {
+                AjaxResult::Ok(v) => Some(v),
+                AjaxResult::SessionDead => None,
+                AjaxResult::Error(e) => return Err(e).context("core_webservice_get_site_info"),
+            },
+        )
+    }
+
+    /// Checks a web service token and finds out who it belongs to
+    #[instrument(skip_all)]
+    pub async fn check_web_service_token(&self, wstoken: &str) -> Result<SessionProbeResult> {
+        let max_wait = Some(self.timeouts.rate_limit_wait);
+        let site_info = match self.site_info(wstoken, max_wait).await? {
+            Some(v) => v,
+            None => {
+                info!("Moodle does not accept the web service token");
+                return Ok(SessionProbeResult::Invalid);
+            }
+        };
+
+        let users = match self
+            .web_service::<Vec<WebServiceUser>>(
+                wstoken,
+                "core_user_get_users_by_field",
+                &[
+                    ("field", "id".to_string()),
+                    ("values[0]", site_info.userid.to_string()),
+                ],
+                max_wait,
+            )
+            .await
+            .context("users_by_field")?
+        {
+            AjaxResult::Ok(v) => v,
+            AjaxResult::SessionDead => return Ok(SessionProbeResult::Invalid),
+            AjaxResult::Error(e) => return Err(e).context("core_user_get_users_by_field"),
+        };
+
+        let email = users
+            .into_iter()
+            .next()
+            .and_then(|u| u.email)
+            .context("Moodle did not report the email of the token owner")?;
+        if !EmailAddress::is_valid(&email) {
+            return Err(anyhow!(
+                "Got email address {}, but it seems to be invalid",
+                email
+            ));
+        }
+
+        info!("Web service token seems to be valid; email = {}", email);
+
+        Ok(SessionProbeResult::Valid {
+            email: Email(email),
+            csrf_session: String::new(),
+        })
+    }
+
+    /// Makes a web service call with the token, so that moodle sees it in use. Returns `false` if the token is dead
+    #[instrument(skip_all)]
+    pub async fn ping_web_service_token(&self, wstoken: &str) -> Result<bool> {
+        Ok(self.site_info(wstoken, None).await?.is_some())
+    }
+
+    /// Fetches a page without a session. Returns the status and the body
+    #[instrument(skip(self))]
+    pub async fn fetch_anonymous(&self, path: &str) -> Result<(StatusCode, String)> {
+        self.wait_for_rate_limit(None).await?;
+
+        let url = self.base_url.join(path.trim_start_matches('/'))?;
+
+        let resp = self
+            .reqwest
+            .get(url)
+            .header(USER_AGENT, self.user_agent())
+            .send()
+            .await?;
+
+        Ok((resp.status(), resp.text().await?))
+    }
+
+    /// Calls the AJAX function without a session and returns the response as it is. Moodle's error links to the
+    /// documentation of its version, which pages only do for logged-in users
+    pub async fn fetch_anonymous_ajax(&self, method_name: &str) -> Result<String> {
+        self.wait_for_rate_limit(None).await?;
+
+        let url = self.base_url.join("lib/ajax/service.php?sesskey=")?;
+
+        let resp = self
+            .reqwest
+            .post(url)
+            .header(USER_AGENT, self.user_agent())
+            .json(&[AjaxPayload {
+                index: 0,
+                methodname: method_name.to_string(),
+                args: serde_json::Map::<String, serde_json::Value>::new(),
+            }])
+            .send()
+            .await?;
+
+        Ok(resp.text().await?)
+    }
+
+    /// Calls the AJAX function without a session to find out whether it's available. Returns the error code if it's not
+    pub async fn probe_ajax_function(&self, method_name: &str) -> Result<Option<String>> {
+        let result = self
+            .ajax::<_, serde_json::Value>(
+                &mut CookieJar::default(),
+                "",
+                method_name,
+                serde_json::Map::<String, serde_json::Value>::new(),
+            )
+            .await?;
+
+        Ok(match result {
+            // the function exists, it just wants a session
+            AjaxResult::Ok(_) | AjaxResult::SessionDead => None,
+            AjaxResult::Error(e) => Some(e.code),
+        })
+    }
+
+    /// Calls the web service API with an invalid token to find out whether it's enabled. Returns the error code if it's not
+    pub async fn probe_web_service(&self) -> Result<Option<String>> {
+        let result = self
+            .web_service::<serde_json::Value>("", "core_webservice_get_site_info", &[], None)
+            .await?;
+
+        Ok(match result {
+            AjaxResult::Ok(_) | AjaxResult::SessionDead => None,
+            AjaxResult::Error(e) => Some(e.code),
+        })
+    }
+
+    async fn touch_session(&self, cookies: &mut CookieJar, csrf_session: &str) -> Result<bool> {
         Ok(
             match self
                 .ajax::<_, bool>(
-                    moodle_session,
+                    cookies,
                     csrf_session,
                     "core_session_touch",
                     serde_json::Map::<String, serde_json::Value>::new(),
@@ -287,13 +721,13 @@ impl Moodle {
 
     async fn remaining_session_time(
         &self,
-        moodle_session: &str,
+        cookies: &mut CookieJar,
         csrf_session: &str,
     ) -> Result<Option<SessionTime>> {
         Ok(
             match self
                 .ajax::<_, SessionTime>(
-                    moodle_session,
+                    cookies,
                     csrf_session,
                     "core_session_time_remaining",
                     serde_json::Map::<String, serde_json::Value>::new(),
@@ -308,18 +742,19 @@ impl Moodle {
         )
     }
 
+    /// Cookies set by moodle are merged into the jar
     #[instrument(skip_all)]
     pub async fn update_session(
         &self,
-        moodle_session: &str,
+        cookies: &mut CookieJar,
         csrf_session: &str,
     ) -> Result<SessionUpdateResult> {
         let touch_result = self
-            .touch_session(moodle_session, csrf_session)
+            .touch_session(cookies, csrf_session)
             .await
             .context("Failed to touch session")?;
         let remaining_time = self
-            .remaining_session_time(moodle_session, csrf_session)
+            .remaining_session_time(cookies, csrf_session)
             .await
             .context("Failed to determine remaining session time")?;
         if touch_result {
@@ -332,3 +767,120 @@ impl Moodle {
         Ok(SessionUpdateResult::SessionDead)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replaying(fixtures: &str, base_url: &str) -> Moodle {
+        let config: config::Moodle = serde_yaml::from_str(&format!(
+            r#"
+base_url: "{}"
+rpm: 6000
+max_burst: 100
+user_agent: "moodle-session-ext tests"
+http:
+  fixtures:
+    mode: "replay"
+    directory: "{}/fixtures/{}"
+"#,
+            base_url,
+            env!("CARGO_MANIFEST_DIR"),
+            fixtures
+        ))
+        .unwrap();
+        Moodle::new(config).unwrap()
+    }
+
+    /// Each fixture set has a valid session that is probed and kept alive, then the same calls after it expired
+    async fn check_fixtures(moodle: Moodle, expected_email: &str, expected_time_left: Duration) {
+        let mut cookies = CookieJar::new("REDACTED");
+
+        let csrf_session = match moodle.check_session(&mut cookies).await.unwrap() {
+            SessionProbeResult::Valid {
+                email,
+                csrf_session,
+            } => {
+                assert_eq!(email.0, expected_email);
+                csrf_session
+            }
+            SessionProbeResult::Invalid => panic!("session should be valid"),
+        };
+        assert_eq!(csrf_session, "REDACTED");
+        match moodle
+            .update_session(&mut cookies, &csrf_session)
+            .await
+            .unwrap()
+        {
+            SessionUpdateResult::Ok { time_left } => assert_eq!(time_left, expected_time_left),
+            SessionUpdateResult::SessionDead => panic!("session should be alive"),
+        }
+
+        assert!(matches!(
+            moodle.check_session(&mut cookies).await.unwrap(),
+            SessionProbeResult::Invalid
+        ));
+        assert!(matches!(
+            moodle
+                .update_session(&mut cookies, &csrf_session)
+                .await
+                .unwrap(),
+            SessionUpdateResult::SessionDead
+        ));
+        assert_eq!(cookies.session(), Some("REDACTED"));
+    }
+
+    #[test]
+    fn joins_paths_below_the_base_url() {
+        for base_url in ["https://example.edu/moodle", "https://example.edu/moodle/"] {
+            let base_url = with_trailing_slash(Url::parse(base_url).unwrap());
+            assert_eq!(
+                base_url.join("user/profile.php").unwrap().as_str(),
+                "https://example.edu/moodle/user/profile.php"
+            );
+            assert_eq!(
+                base_url
+                    .join("lib/ajax/service.php?sesskey=abc")
+                    .unwrap()
+                    .as_str(),
+                "https://example.edu/moodle/lib/ajax/service.php?sesskey=abc"
+            );
+        }
+
+        let base_url = with_trailing_slash(Url::parse("https://moodle.example.edu").unwrap());
+        assert_eq!(
+            base_url.join("user/profile.php").unwrap().as_str(),
+            "https://moodle.example.edu/user/profile.php"
+        );
+    }
+
+    #[tokio::test]
+    async fn moodle_3_11_english() {
+        check_fixtures(
+            replaying("moodle-3.11-en", "https://moodle.example.edu"),
+            "ada.student@example.edu",
+            Duration::from_secs(7140),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn moodle_4_1_english() {
+        check_fixtures(
+            replaying("moodle-4.1-en", "https://moodle.example.edu/"),
+            "ada.student@example.edu",
+            Duration::from_secs(28740),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn moodle_4_1_german_under_a_sub_path() {
+        check_fixtures(
+            replaying("moodle-4.1-de", "https://lernen.example.de/moodle"),
+            "jana.mueller@uni.example.de",
+            Duration::from_secs(6900),
+        )
+        .await;
+    }
+}
@@ -0,0 +1,533 @@
+use crate::model::{Email, NotificationKind, Token};
+use crate::{config, Database};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use humantime_serde::re::humantime;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::{debug, error, info, instrument};
+
+#[derive(Debug)]
+struct Event {
+    owner: Email,
+    kind: NotificationKind,
+    /// When the affected session was added, so that the user can tell their sessions apart
+    added: SystemTime,
+    error: Option<String>,
+}
+
+/// Queues notifications about sessions for their owners. Does nothing if notifications are not configured
+pub struct Notifier {
+    events: Option<mpsc::UnboundedSender<Event>>,
+    failure_threshold: u32,
+}
+
+/// Sends the notifications queued by `Notifier`, batching them per user
+pub struct Mailer {
+    inner: Option<MailerInner>,
+}
+
+struct MailerInner {
+    config: config::Notifications,
+    db: Arc<Database>,
+    from: Mailbox,
+    transport: Box<dyn Transport>,
+    events: mpsc::UnboundedReceiver<Event>,
+}
+
+/// Where the emails are delivered to, an SMTP relay outside of tests
+#[async_trait]
+trait Transport: Send + Sync {
+    async fn send(&self, message: Message) -> Result<()>;
+}
+
+#[async_trait]
+impl Transport for AsyncSmtpTransport<Tokio1Executor> {
+    async fn send(&self, message: Message) -> Result<()> {
+        AsyncTransport::send(self, message).await?;
+        Ok(())
+    }
+}
+
+fn make_transport(smtp: &config::Smtp) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    let mut builder = match smtp.security {
+        config::SmtpSecurity::None => {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host)
+        }
+        config::SmtpSecurity::Starttls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?
+        }
+        config::SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+    };
+    if let Some(port) = smtp.port {
+        builder = builder.port(port);
+    }
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        builder = builder.credentials(Credentials::new(
+            username.clone(),
+            password.expose().to_string(),
+        ));
+    }
+
+    Ok(builder.build())
+}
+
+pub fn new(config: Option<config::Notifications>, db: Arc<Database>) -> Result<(Notifier, Mailer)> {
+    let config = match config {
+        None => {
+            info!("Notifications are not configured, users won't be notified");
+            return Ok((
+                Notifier {
+                    events: None,
+                    failure_threshold: 0,
+                },
+                Mailer { inner: None },
+            ));
+        }
+        Some(c) => c,
+    };
+
+    let transport = make_transport(&config.smtp).context("Setting up the SMTP transport")?;
+    with_transport(config, db, Box::new(transport))
+}
+
+fn with_transport(
+    config: config::Notifications,
+    db: Arc<Database>,
+    transport: Box<dyn Transport>,
+) -> Result<(Notifier, Mailer)> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let notifier = Notifier {
+        events: Some(sender),
+        failure_threshold: config.failure_threshold,
+    };
+    let mailer = Mailer {
+        inner: Some(MailerInner {
+            from: config.from.parse().context("Parsing notifications.from")?,
+            transport,
+            config,
+            db,
+            events: receiver,
+        }),
+    };
+
+    Ok((notifier, mailer))
+}
+
+impl Notifier {
+    fn send(&self, token: &Token, kind: NotificationKind) {
+        if let Some(events) = &self.events {
+            // the mailer only stops together with the whole app
+            let _ = events.send(Event {
+                owner: token.owner.clone(),
+                kind,
+                added: token.added,
+                error: token.last_error.clone(),
+            });
+        }
+    }
+
+    pub fn session_died(&self, token: &Token) {
+        self.send(token, NotificationKind::SessionDead);
+    }
+
+    /// Notifies the owner once the token has failed to refresh `failure_threshold` times in a row
+    pub fn refresh_failed(&self, token: &Token) {
+        if token.consecutive_failures == self.failure_threshold {
+            self.send(token, NotificationKind::RefreshFailing);
+        }
+    }
+}
+
+fn render(template: &str, email: &Email, events: &[Event]) -> String {
+    let sessions = events
+        .iter()
+        .map(|e| {
+            let added = humantime::format_rfc3339_seconds(e.added);
+            match &e.error {
+                Some(error) if e.kind == NotificationKind::RefreshFailing => {
+                    format!("- session added at {} ({})", added, error)
+                }
+                _ => format!("- session added at {}", added),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    template
+        .replace("{email}", &email.0)
+        .replace("{count}", &events.len().to_string())
+        .replace("{sessions}", &sessions)
+}
+
+impl MailerInner {
+    #[instrument(skip(self, events), fields(email = %email.0))]
+    async fn notify(&self, email: &Email, kind: NotificationKind, events: &[Event]) -> Result<()> {
+        let user = match self.db.get_user(email)? {
+            None => return Ok(()),
+            Some(u) => u,
+        };
+        if user.notifications_opted_out {
+            debug!("User opted out of notifications");
+            return Ok(());
+        }
+
+        let now = self.db.clock().now();
+        if let Some(last) = user.last_notified.get(&kind) {
+            if now.duration_since(*last).unwrap_or_default() < self.config.cooldown {
+                debug!("User was notified about {:?} recently, skipping", kind);
+                return Ok(());
+            }
+        }
+
+        let template = match kind {
+            NotificationKind::SessionDead => &self.config.templates.session_dead,
+            NotificationKind::RefreshFailing => &self.config.templates.refresh_failing,
+        };
+        let to: Address = email.0.parse().context("Parsing the user email")?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(Mailbox::new(None, to))
+            .subject(render(&template.subject, email, events))
+            .header(ContentType::TEXT_PLAIN)
+            .body(render(&template.body, email, events))?;
+
+        self.transport
+            .send(message)
+            .await
+            .context("Sending the email")?;
+        info!("Notified user about {} sessions ({:?})", events.len(), kind);
+
+        self.db.record_notification(email, kind, now)?;
+
+        Ok(())
+    }
+
+    async fn run(mut self) -> Result<()> {
+        while let Some(first) = self.events.recv().await {
+            let mut batch = vec![first];
+
+            let window = sleep(self.config.batch_window);
+            tokio::pin!(window);
+            loop {
+                select! {
+                    _ = &mut window => break,
+                    event = self.events.recv() => match event {
+                        Some(event) => batch.push(event),
+                        None => break,
+                    },
+                }
+            }
+
+            let mut grouped: HashMap<(String, NotificationKind), Vec<Event>> = HashMap::new();
+            for event in batch {
+                grouped
+                    .entry((event.owner.0.clone(), event.kind))
+                    .or_default()
+                    .push(event);
+            }
+
+            for ((_, kind), events) in grouped {
+                let email = events[0].owner.clone();
+                if let Err(e) = self.notify(&email, kind, &events).await {
+                    error!("Failed to notify {}: {:?}", email.0, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Mailer {
+    pub async fn run(self) -> Result<()> {
+        match self.inner {
+            Some(inner) => inner.run().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TokioClock;
+    use crate::cookies::CookieJar;
+    use crate::model::Credential;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    /// Keeps the sent emails instead of delivering them
+    #[derive(Clone, Default)]
+    struct Outbox(Arc<Mutex<Vec<Message>>>);
+
+    #[async_trait]
+    impl Transport for Outbox {
+        async fn send(&self, message: Message) -> Result<()> {
+            self.0.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    impl Outbox {
+        /// Recipient and subject of each email sent so far, sorted as the batches are sent in no particular order
+        fn sent(&self) -> Vec<(String, String)> {
+            let mut sent = self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|message| {
+                    (
+                        message.envelope().to()[0].to_string(),
+                        message.headers().get_raw("Subject").unwrap().to_string(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            sent.sort();
+            sent
+        }
+    }
+
+    struct Harness {
+        _dir: TempDir,
+        db: Arc<Database>,
+        notifier: Notifier,
+        outbox: Outbox,
+        mailer: tokio::task::JoinHandle<Result<()>>,
+    }
+
+    impl Harness {
+        fn start() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            let db = Arc::new(
+                Database::new(
+                    config::Database {
+                        path: dir.path().join("db").try_into().unwrap(),
+                        encryption_key: None,
+                    },
+                    Arc::new(TokioClock::new(start)),
+                )
+                .unwrap(),
+            );
+            let template = |subject: &str| config::Template {
+                subject: subject.to_string(),
+                body: "{sessions}".to_string(),
+            };
+            let config = config::Notifications {
+                smtp: config::Smtp {
+                    host: "localhost".to_string(),
+                    port: None,
+                    security: config::SmtpSecurity::None,
+                    username: None,
+                    password: None,
+                },
+                from: "noreply@example.edu".to_string(),
+                failure_threshold: 3,
+                batch_window: MINUTE,
+                cooldown: 60 * MINUTE,
+                templates: config::Templates {
+                    session_dead: template("{count} sessions died"),
+                    refresh_failing: template("{count} sessions are failing"),
+                },
+            };
+            let outbox = Outbox::default();
+            let (notifier, mailer) =
+                with_transport(config, db.clone(), Box::new(outbox.clone())).unwrap();
+
+            Self {
+                _dir: dir,
+                db,
+                notifier,
+                outbox,
+                mailer: tokio::spawn(mailer.run()),
+            }
+        }
+
+        fn add_token(&self, email: &str, session: &str) -> Token {
+            self.db
+                .add_token(
+                    &Email(email.to_string()),
+                    &Credential::BrowserSession {
+                        moodle_session: session.to_string(),
+                        csrf_session: "sesskey".to_string(),
+                        cookies: CookieJar::new(session),
+                    },
+                    None,
+                    &config::UserLimit {
+                        max_sessions: 10,
+                        when_reached: config::WhenLimitReached::Reject,
+                    },
+                )
+                .unwrap()
+                .token
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            self.mailer.abort();
+        }
+    }
+
+    fn sent(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(to, subject)| (to.to_string(), subject.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn renders_the_sessions_into_the_template() {
+        let added = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let event = |kind, error: Option<&str>| Event {
+            owner: Email("student@example.edu".to_string()),
+            kind,
+            added,
+            error: error.map(str::to_string),
+        };
+        let template = "Hi {email}, {count} sessions:\n{sessions}";
+
+        assert_eq!(
+            render(
+                template,
+                &Email("student@example.edu".to_string()),
+                &[
+                    event(NotificationKind::RefreshFailing, Some("moodle is down")),
+                    event(NotificationKind::RefreshFailing, None),
+                ]
+            ),
+            "Hi student@example.edu, 2 sessions:\n\
+             - session added at 2023-11-14T22:13:20Z (moodle is down)\n\
+             - session added at 2023-11-14T22:13:20Z"
+        );
+        // the error of a dead session is not worth telling
+        assert_eq!(
+            render(
+                "{sessions}",
+                &Email("student@example.edu".to_string()),
+                &[event(NotificationKind::SessionDead, Some("logged out"))]
+            ),
+            "- session added at 2023-11-14T22:13:20Z"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_events_per_user_and_kind() {
+        let harness = Harness::start();
+        let a1 = harness.add_token("a@example.edu", "a1");
+        let a2 = harness.add_token("a@example.edu", "a2");
+        let b1 = harness.add_token("b@example.edu", "b1");
+
+        harness.notifier.session_died(&a1);
+        sleep(MINUTE / 2).await;
+        harness.notifier.session_died(&a2);
+        harness.notifier.session_died(&b1);
+        harness.notifier.refresh_failed(&Token {
+            consecutive_failures: 3,
+            ..a2.clone()
+        });
+        assert_eq!(harness.outbox.sent(), sent(&[]));
+
+        sleep(MINUTE).await;
+        assert_eq!(
+            harness.outbox.sent(),
+            sent(&[
+                ("a@example.edu", "1 sessions are failing"),
+                ("a@example.edu", "2 sessions died"),
+                ("b@example.edu", "1 sessions died"),
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn notifies_about_failing_refreshes_once_the_threshold_is_reached() {
+        let harness = Harness::start();
+        let token = harness.add_token("a@example.edu", "a1");
+
+        for consecutive_failures in [1, 2, 4] {
+            harness.notifier.refresh_failed(&Token {
+                consecutive_failures,
+                ..token.clone()
+            });
+        }
+        sleep(2 * MINUTE).await;
+        assert_eq!(harness.outbox.sent(), sent(&[]));
+
+        harness.notifier.refresh_failed(&Token {
+            consecutive_failures: 3,
+            ..token
+        });
+        sleep(2 * MINUTE).await;
+        assert_eq!(
+            harness.outbox.sent(),
+            sent(&[("a@example.edu", "1 sessions are failing")])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_notify_again_within_the_cooldown() {
+        let harness = Harness::start();
+        let a1 = harness.add_token("a@example.edu", "a1");
+        let a2 = harness.add_token("a@example.edu", "a2");
+
+        harness.notifier.session_died(&a1);
+        sleep(2 * MINUTE).await;
+        harness.notifier.session_died(&a2);
+        sleep(2 * MINUTE).await;
+        assert_eq!(
+            harness.outbox.sent(),
+            sent(&[("a@example.edu", "1 sessions died")])
+        );
+
+        sleep(60 * MINUTE).await;
+        harness.notifier.session_died(&a2);
+        sleep(2 * MINUTE).await;
+        assert_eq!(
+            harness.outbox.sent(),
+            sent(&[
+                ("a@example.edu", "1 sessions died"),
+                ("a@example.edu", "1 sessions died"),
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_notify_users_who_opted_out() {
+        let harness = Harness::start();
+        let a1 = harness.add_token("a@example.edu", "a1");
+        let b1 = harness.add_token("b@example.edu", "b1");
+        assert!(harness
+            .db
+            .set_notifications_opted_out(&Email("a@example.edu".to_string()), true)
+            .unwrap());
+
+        harness.notifier.session_died(&a1);
+        harness.notifier.session_died(&b1);
+        sleep(2 * MINUTE).await;
+
+        assert_eq!(
+            harness.outbox.sent(),
+            sent(&[("b@example.edu", "1 sessions died")])
+        );
+        let user = harness
+            .db
+            .get_user(&Email("a@example.edu".to_string()))
+            .unwrap()
+            .unwrap();
+        assert!(user.last_notified.is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{config, Moodle};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::fmt::{Display, Formatter};
+use tracing::{error, info, warn};
+
+/// Pages of logged-in users and AJAX errors link to the documentation of their moodle version, e.g.
+/// `https://docs.moodle.org/401/en/` for 4.1
+static DOCS_VERSION_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"docs\.moodle\.org/(\d)(\d{1,2})/"#).unwrap());
+
+/// AJAX functions the `ajax_touch` keep-alive strategy relies on
+const AJAX_TOUCH_FUNCTIONS: &[&str] = &["core_session_touch", "core_session_time_remaining"];
+
+#[derive(Debug)]
+pub enum Outcome {
+    Ok(String),
+    /// Not needed for the configured strategy, but something users may run into
+    Warning(String),
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+/// What the moodle instance supports, as found out by `run`
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn push(&mut self, name: impl Into<String>, outcome: Outcome) {
+        self.checks.push(Check {
+            name: name.into(),
+            outcome,
+        });
+    }
+
+    pub fn failed(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|c| matches!(c.outcome, Outcome::Failed(_)))
+    }
+
+    pub fn log(&self) {
+        for check in &self.checks {
+            match &check.outcome {
+                Outcome::Ok(m) => info!("Probe: {}: {}", check.name, m),
+                Outcome::Warning(m) => warn!("Probe: {}: {}", check.name, m),
+                Outcome::Failed(m) => error!("Probe: {}: {}", check.name, m),
+            }
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for check in &self.checks {
+            let (status, message) = match &check.outcome {
+                Outcome::Ok(m) => ("ok", m),
+                Outcome::Warning(m) => ("warning", m),
+                Outcome::Failed(m) => ("FAILED", m),
+            };
+            writeln!(f, "[{:>7}] {}: {}", status, check.name, message)?;
+        }
+        Ok(())
+    }
+}
+
+fn docs_version(page: &str) -> Option<String> {
+    let captures = DOCS_VERSION_REGEX.captures(page)?;
+    let minor: u32 = captures[2].parse().ok()?;
+    Some(format!("{}.{}", &captures[1], minor))
+}
+
+/// The version from the `moreinfourl` of an AJAX error, e.g. the one for a call without a session
+fn ajax_error_version(response: &str) -> Option<String> {
+    let response: serde_json::Value = serde_json::from_str(response).ok()?;
+    docs_version(response[0]["exception"]["moreinfourl"].as_str()?)
+}
+
+/// The login page only links to the documentation for logged-in users (who get redirected away from it), so this
+/// falls back to the error of an AJAX call without a session
+async fn version(moodle: &Moodle, login_page: &str) -> Option<String> {
+    if let Some(version) = docs_version(login_page) {
+        return Some(version);
+    }
+
+    match moodle.fetch_anonymous_ajax(AJAX_TOUCH_FUNCTIONS[0]).await {
+        Ok(response) => ajax_error_version(&response),
+        Err(e) => {
+            warn!("Could not call moodle to find out its version: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Checks that the moodle instance is reachable and supports what the configured keep-alive strategy needs
+pub async fn run(moodle: &Moodle, config: &config::Moodle) -> Report {
+    let mut report = Report::default();
+
+    let login_page = match moodle.fetch_anonymous("login/index.php").await {
+        // sites with single sign-on redirect away from the login page
+        Ok((status, body)) if status.is_success() || status.is_redirection() => {
+            report.push(
+                "reachability",
+                Outcome::Ok(format!("{} is reachable", moodle.base_url())),
+            );
+            body
+        }
+        Ok((status, _)) => {
+            report.push(
+                "reachability",
+                Outcome::Failed(format!("the login page responded with {}", status)),
+            );
+            return report;
+        }
+        Err(e) => {
+            report.push("reachability", Outcome::Failed(format!("{:#}", e)));
+            return report;
+        }
+    };
+
+    report.push(
+        "version",
+        match version(moodle, &login_page).await {
+            Some(version) => Outcome::Ok(format!("moodle {}", version)),
+            None => Outcome::Warning(
+                "could not be determined from the login page or an AJAX error".to_string(),
+            ),
+        },
+    );
+
+    match &config.keep_alive {
+        config::KeepAlive::AjaxTouch => {
+            for function in AJAX_TOUCH_FUNCTIONS {
+                let outcome = match moodle.probe_ajax_function(function).await {
+                    Ok(None) => Outcome::Ok("available".to_string()),
+                    Ok(Some(code)) => Outcome::Failed(format!(
+                        "not available over AJAX ({}), consider the page_fetch keep-alive strategy",
+                        code
+                    )),
+                    Err(e) => Outcome::Failed(format!("{:#}", e)),
+                };
+                report.push(format!("ajax {}", function), outcome);
+            }
+        }
+        config::KeepAlive::PageFetch { path, .. } => {
+            let outcome = match moodle.fetch_anonymous(path).await {
+                Ok((status, _)) if status.is_success() || status.is_redirection() => {
+                    Outcome::Ok(format!("responds with {}", status))
+                }
+                Ok((status, _)) => Outcome::Failed(format!("responds with {}", status)),
+                Err(e) => Outcome::Failed(format!("{:#}", e)),
+            };
+            report.push(format!("page {}", path), outcome);
+        }
+    }
+
+    report.push(
+        "web services",
+        match moodle.probe_web_service().await {
+            Ok(None) => Outcome::Ok("enabled".to_string()),
+            Ok(Some(code)) => Outcome::Warning(format!(
+                "not available ({}), web service tokens won't be accepted",
+                code
+            )),
+            Err(e) => Outcome::Warning(format!("{:#}", e)),
+        },
+    );
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bodies of `fixtures/moodle-3.11-en/0004.json` and `fixtures/moodle-4.1-de/0004.json`
+    const AJAX_ERROR_3_11: &str = r#"[{"error":true,"exception":{"message":"Your session has most likely timed out. Please log in again.","errorcode":"servicerequireslogin","link":"https:\/\/moodle.example.edu\/","moreinfourl":"https:\/\/docs.moodle.org\/311\/en\/error\/moodle\/servicerequireslogin"}}]"#;
+    const AJAX_ERROR_4_1: &str = r#"[{"error":true,"exception":{"message":"Ihre Sitzung ist wahrscheinlich abgelaufen. Bitte melden Sie sich erneut an.","errorcode":"servicerequireslogin","link":"https:\/\/lernen.example.de\/moodle\/","moreinfourl":"https:\/\/docs.moodle.org\/401\/de\/error\/moodle\/servicerequireslogin"}}]"#;
+
+    #[test]
+    fn reads_the_version_from_documentation_links() {
+        let page =
+            r#"<a href="https://docs.moodle.org/401/en/login/index">Help and documentation</a>"#;
+        assert_eq!(docs_version(page).as_deref(), Some("4.1"));
+        let page =
+            r#"<a href="https://docs.moodle.org/311/en/my/index">Moodle Docs for this page</a>"#;
+        assert_eq!(docs_version(page).as_deref(), Some("3.11"));
+    }
+
+    #[test]
+    fn anonymous_login_pages_have_no_version() {
+        let page = r#"<link rel="stylesheet" type="text/css" href="https://moodle.example.edu/theme/yui_combo.php?rollup/3.17.2/yui-moodlesimple-min.css" />
+<a href="https://docs.moodle.org/en/Forgotten_password">Forgotten your password?</a>"#;
+        assert_eq!(docs_version(page), None);
+    }
+
+    #[test]
+    fn reads_the_version_from_ajax_errors() {
+        assert_eq!(ajax_error_version(AJAX_ERROR_3_11).as_deref(), Some("3.11"));
+        assert_eq!(ajax_error_version(AJAX_ERROR_4_1).as_deref(), Some("4.1"));
+    }
+
+    #[test]
+    fn ajax_errors_without_documentation_links_have_no_version() {
+        // older versions report errors without an exception
+        let response = r#"[{"error":"Invalid session key","errorcode":"invalidsesskey"}]"#;
+        assert_eq!(ajax_error_version(response), None);
+        assert_eq!(ajax_error_version("<html>Maintenance</html>"), None);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{config, Config, Moodle};
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info, instrument};
+
+/// Reloads the config on SIGHUP and applies the changes that can be made without a restart
+pub struct Reloader {
+    config_path: Utf8PathBuf,
+    config_override_path: Option<Utf8PathBuf>,
+    current: Config,
+    moodle: Arc<Moodle>,
+    updater: watch::Sender<config::Updater>,
+    server: watch::Sender<config::Server>,
+}
+
+/// Lists the settings that differ between the configs, but can't be changed at runtime
+fn restart_required_changes(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+    if old.database != new.database {
+        changes.push("database");
+    }
+    if old.moodle.base_url != new.moodle.base_url {
+        changes.push("moodle.base_url");
+    }
+    if old.moodle.session_cookie != new.moodle.session_cookie {
+        changes.push("moodle.session_cookie");
+    }
+    if old.moodle.keep_alive != new.moodle.keep_alive {
+        changes.push("moodle.keep_alive");
+    }
+    if old.moodle.http != new.moodle.http {
+        changes.push("moodle.http");
+    }
+    if old.moodle.startup_probe != new.moodle.startup_probe {
+        changes.push("moodle.startup_probe");
+    }
+    if old.server.endpoints != new.server.endpoints {
+        changes.push("server.endpoints");
+    }
+    if old.notifications != new.notifications {
+        changes.push("notifications");
+    }
+    if old.webhooks != new.webhooks {
+        changes.push("webhooks");
+    }
+    changes
+}
+
+impl Reloader {
+    pub fn new(
+        config_path: Utf8PathBuf,
+        config_override_path: Option<Utf8PathBuf>,
+        current: Config,
+        moodle: Arc<Moodle>,
+    ) -> (
+        Self,
+        watch::Receiver<config::Updater>,
+        watch::Receiver<config::Server>,
+    ) {
+        let (updater, updater_rx) = watch::channel(current.updater.clone());
+        let (server, server_rx) = watch::channel(current.server.clone());
+
+        (
+            Self {
+                config_path,
+                config_override_path,
+                current,
+                moodle,
+                updater,
+                server,
+            },
+            updater_rx,
+            server_rx,
+        )
+    }
+
+    #[instrument(skip(self))]
+    fn reload(&mut self) -> Result<()> {
+        let new = Config::load(&self.config_path, self.config_override_path.as_deref())?;
+
+        let restart_required = restart_required_changes(&self.current, &new);
+        if !restart_required.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Changes to {} require a restart, refusing to apply the new config",
+                restart_required.join(", ")
+            ));
+        }
+
+        if (self.current.moodle.rpm, self.current.moodle.max_burst)
+            != (new.moodle.rpm, new.moodle.max_burst)
+        {
+            info!(
+                "Changing rate limit to {} rpm with a burst of {}",
+                new.moodle.rpm, new.moodle.max_burst
+            );
+            self.moodle
+                .set_rate_limit(new.moodle.rpm, new.moodle.max_burst);
+        }
+        if self.current.moodle.user_agent != new.moodle.user_agent {
+            info!("Changing user agent to {:?}", new.moodle.user_agent);
+            self.moodle.set_user_agent(&new.moodle.user_agent)?;
+        }
+        if self.current.updater != new.updater {
+            info!("Changing updater config to {:?}", new.updater);
+            self.updater.send_replace(new.updater.clone());
+        }
+        if self.current.server != new.server {
+            info!("Changing server config to {:?}", new.server);
+            self.server.send_replace(new.server.clone());
+        }
+
+        self.current = new;
+
+        Ok(())
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading config");
+            match self.reload() {
+                Ok(()) => info!("Config reloaded"),
+                Err(e) => error!("Failed to reload config: {:?}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StartupProbe;
+    use tempfile::TempDir;
+
+    const CONFIG: &str = include_str!("../config.yml");
+
+    fn example() -> Config {
+        serde_yaml::from_str(CONFIG).unwrap()
+    }
+
+    #[test]
+    fn runtime_settings_can_change_without_a_restart() {
+        let old = example();
+        let mut new = example();
+        new.moodle.rpm = 60;
+        new.moodle.max_burst = 10;
+        new.moodle.user_agent = "moodle-session-ext tests".to_string();
+        new.updater.jitter = 0.0;
+        new.server.cors.allowed_methods.push("GET".to_string());
+
+        assert!(restart_required_changes(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn lists_the_settings_that_need_a_restart() {
+        let old = example();
+        let mut new = example();
+        new.database.path = "other.db".into();
+        new.moodle.base_url = "https://moodle.example.edu/".parse().unwrap();
+        new.moodle.startup_probe = StartupProbe::Require;
+        new.server.endpoints.clear();
+        new.moodle.rpm = 60;
+
+        assert_eq!(
+            restart_required_changes(&old, &new),
+            vec![
+                "database",
+                "moodle.base_url",
+                "moodle.startup_probe",
+                "server.endpoints"
+            ]
+        );
+    }
+
+    struct Harness {
+        dir: TempDir,
+        reloader: Reloader,
+        updater: watch::Receiver<config::Updater>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let config_path = Utf8PathBuf::try_from(dir.path().join("config.yml")).unwrap();
+            std::fs::write(&config_path, CONFIG).unwrap();
+            let override_path = Utf8PathBuf::try_from(dir.path().join("override.yml")).unwrap();
+            std::fs::write(&override_path, "{}").unwrap();
+
+            let config = example();
+            let moodle = Arc::new(Moodle::new(config.moodle.clone()).unwrap());
+            let (reloader, updater, _) =
+                Reloader::new(config_path, Some(override_path), config, moodle);
+
+            Self {
+                dir,
+                reloader,
+                updater,
+            }
+        }
+
+        fn reload_with(&mut self, override_config: &str) -> Result<()> {
+            std::fs::write(self.dir.path().join("override.yml"), override_config).unwrap();
+            self.reloader.reload()
+        }
+    }
+
+    #[test]
+    fn applies_runtime_changes() {
+        let mut harness = Harness::new();
+
+        harness
+            .reload_with("moodle:\n  rpm: 60\nupdater:\n  jitter: 0\n")
+            .unwrap();
+
+        assert_eq!(harness.reloader.current.moodle.rpm, 60);
+        assert_eq!(harness.updater.borrow().jitter, 0.0);
+    }
+
+    #[test]
+    fn refuses_the_whole_config_if_a_change_needs_a_restart() {
+        let mut harness = Harness::new();
+
+        let error = harness
+            .reload_with("moodle:\n  rpm: 60\n  startup_probe: \"off\"\n")
+            .unwrap_err();
+
+        assert!(error.to_string().contains("moodle.startup_probe"));
+        assert_eq!(harness.reloader.current.moodle.rpm, 120);
+        assert_eq!(
+            harness.reloader.current.moodle.startup_probe,
+            StartupProbe::Warn
+        );
+        assert!(!harness.updater.has_changed().unwrap());
+    }
+}
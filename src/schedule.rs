@@ -0,0 +1,165 @@
+use crate::config;
+use rand::Rng;
+use std::time::Duration;
+use tracing::debug;
+
+/// How much a single observation moves the estimate
+const ESTIMATE_WEIGHT: f64 = 0.2;
+
+/// Learns the session timeout of a moodle instance from the remaining time it reports right after a session is touched
+#[derive(Debug, Default)]
+pub struct SessionTimeoutEstimator {
+    estimate: Option<Duration>,
+}
+
+impl SessionTimeoutEstimator {
+    pub fn observe(&mut self, time_left: Duration) {
+        let estimate = match self.estimate {
+            None => time_left,
+            Some(estimate) => {
+                estimate.mul_f64(1.0 - ESTIMATE_WEIGHT) + time_left.mul_f64(ESTIMATE_WEIGHT)
+            }
+        };
+        debug!("Estimated session timeout is now {:?}", estimate);
+        self.estimate = Some(estimate);
+    }
+
+    pub fn estimate(&self) -> Option<Duration> {
+        self.estimate
+    }
+}
+
+/// Computes how long to wait before refreshing a session that has `time_left` before it expires
+pub fn refresh_in(
+    config: &config::Updater,
+    estimator: &SessionTimeoutEstimator,
+    time_left: Duration,
+) -> Duration {
+    jittered(config, interval(&config.schedule, estimator, time_left))
+}
+
+/// Shortens the interval by a random fraction of up to `config.jitter`
+pub fn jittered(config: &config::Updater, interval: Duration) -> Duration {
+    // only ever refresh earlier than planned, so that jitter can't make us miss the deadline
+    let jitter = rand::thread_rng().gen_range(0.0..=config.jitter);
+    interval.mul_f64(1.0 - jitter)
+}
+
+fn interval(
+    schedule: &config::Schedule,
+    estimator: &SessionTimeoutEstimator,
+    time_left: Duration,
+) -> Duration {
+    match *schedule {
+        config::Schedule::FixedGap { gap } => time_left.saturating_sub(gap),
+        config::Schedule::Adaptive {
+            fraction,
+            min_interval,
+            max_interval,
+        } => {
+            let time_left = match estimator.estimate() {
+                Some(timeout) => time_left.min(timeout),
+                None => time_left,
+            };
+            let interval = time_left
+                .mul_f64(fraction)
+                .clamp(min_interval, max_interval);
+
+            // don't let the minimal interval make us miss the deadline
+            if interval < time_left {
+                interval
+            } else {
+                time_left.mul_f64(fraction)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn adaptive() -> config::Schedule {
+        config::Schedule::Adaptive {
+            fraction: 0.5,
+            min_interval: MINUTE,
+            max_interval: 120 * MINUTE,
+        }
+    }
+
+    #[test]
+    fn fixed_gap_refreshes_the_gap_before_the_deadline() {
+        let schedule = config::Schedule::FixedGap { gap: 5 * MINUTE };
+        let estimator = SessionTimeoutEstimator::default();
+        assert_eq!(interval(&schedule, &estimator, 30 * MINUTE), 25 * MINUTE);
+        assert_eq!(interval(&schedule, &estimator, 3 * MINUTE), Duration::ZERO);
+    }
+
+    #[test]
+    fn adaptive_waits_a_fraction_of_the_time_left() {
+        let estimator = SessionTimeoutEstimator::default();
+        assert_eq!(interval(&adaptive(), &estimator, 30 * MINUTE), 15 * MINUTE);
+    }
+
+    #[test]
+    fn adaptive_is_clamped_to_the_interval_limits() {
+        let estimator = SessionTimeoutEstimator::default();
+        assert_eq!(
+            interval(&adaptive(), &estimator, 8 * 60 * MINUTE),
+            120 * MINUTE
+        );
+        assert_eq!(interval(&adaptive(), &estimator, 3 * MINUTE / 2), MINUTE);
+        // the minimal interval would be past the deadline
+        assert_eq!(interval(&adaptive(), &estimator, MINUTE / 2), MINUTE / 4);
+    }
+
+    #[test]
+    fn adaptive_caps_the_time_left_by_the_learned_timeout() {
+        let mut estimator = SessionTimeoutEstimator::default();
+        estimator.observe(30 * MINUTE);
+        assert_eq!(interval(&adaptive(), &estimator, 60 * MINUTE), 15 * MINUTE);
+        assert_eq!(interval(&adaptive(), &estimator, 20 * MINUTE), 10 * MINUTE);
+    }
+
+    #[test]
+    fn estimator_starts_at_the_first_observation_and_converges() {
+        let mut estimator = SessionTimeoutEstimator::default();
+        assert_eq!(estimator.estimate(), None);
+
+        estimator.observe(60 * MINUTE);
+        assert_eq!(estimator.estimate(), Some(60 * MINUTE));
+
+        for _ in 0..50 {
+            estimator.observe(30 * MINUTE);
+        }
+        let estimate = estimator.estimate().unwrap();
+        assert!(estimate >= 30 * MINUTE && estimate < 30 * MINUTE + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_only_ever_shortens_the_interval() {
+        let config = config::Updater {
+            schedule: adaptive(),
+            jitter: 0.1,
+            smoothing: config::Smoothing::default(),
+            web_service_interval: 360 * MINUTE,
+        };
+        for _ in 0..1000 {
+            let jittered = jittered(&config, 30 * MINUTE);
+            assert!(jittered >= 27 * MINUTE && jittered <= 30 * MINUTE);
+        }
+    }
+
+    #[test]
+    fn no_jitter_keeps_the_interval() {
+        let config = config::Updater {
+            schedule: adaptive(),
+            jitter: 0.0,
+            smoothing: config::Smoothing::default(),
+            web_service_interval: 360 * MINUTE,
+        };
+        assert_eq!(jittered(&config, 30 * MINUTE), 30 * MINUTE);
+    }
+}
@@ -1,84 +1,202 @@
-use crate::moodle::SessionProbeResult;
-use crate::{config, Database, Moodle};
+use crate::events::Events;
+use crate::tls::CertificateResolver;
+use crate::{api, config, Database, Moodle};
 use actix_cors::Cors;
-use actix_web::{post, web, App, HttpServer, Responder, Result};
-use serde::{Deserialize, Serialize};
+use actix_web::{post, web, App, HttpServer};
+use serde::Serialize;
+use std::net::TcpListener;
 use std::sync::Arc;
-use tracing::{error, info};
+use tokio::select;
+use tokio::sync::watch;
+use tracing::{info, warn};
 use tracing_actix_web::TracingLogger;
 
 #[derive(Clone)]
-struct Data {
-    db: Arc<Database>,
-    moodle: Arc<Moodle>,
-}
-
-#[derive(Deserialize)]
-struct ExtendRequest {
-    pub moodle_session: String,
+pub struct Data {
+    pub db: Arc<Database>,
+    pub moodle: Arc<Moodle>,
+    pub events: Arc<Events>,
+    pub config: Arc<config::Server>,
+    /// Set when this server is being stopped, so that streaming responses end instead of holding up the restart
+    pub stopping: watch::Receiver<bool>,
 }
 
+/// Response of the unversioned route, kept as it is for the extension versions relying on it
 #[derive(Serialize)]
-struct ExtendResponse {
+struct LegacyExtendResponse {
     pub result: bool,
     pub email: Option<String>,
 }
 
-fn wrap_result<T>(result: anyhow::Result<T>) -> Result<T> {
-    result.map_err(|e| {
-        error!("Encountered an error: {}", e);
-        actix_web::error::ErrorInternalServerError(e)
-    })
-}
-
+/// Compatibility alias of `/v1/extend-session`, reporting an invalid session with `result: false`
 #[post("/extend-session")]
-async fn extend_session(
+async fn legacy_extend_session(
     data: web::Data<Data>,
-    request: web::Json<ExtendRequest>,
-) -> Result<impl Responder> {
-    let moodle_session = &request.moodle_session;
-
-    let email = match wrap_result(data.moodle.check_session(moodle_session).await)? {
-        SessionProbeResult::Invalid => {
-            info!("Moodle session {} is invalid", moodle_session);
-            None
-        }
-        SessionProbeResult::Valid {
-            email,
-            csrf_session,
-        } => {
-            info!("Provided token is valid, adding to database");
-            wrap_result(data.db.add_token(&email, moodle_session, &csrf_session))?;
-            Some(email.0)
-        }
-    };
+    request: web::Json<api::ExtendRequest>,
+) -> Result<web::Json<LegacyExtendResponse>, api::ApiError> {
+    let email = api::extend(&data, &request).await?;
 
-    Ok(web::Json(ExtendResponse {
+    Ok(web::Json(LegacyExtendResponse {
         result: email.is_some(),
         email,
     }))
 }
 
+fn is_origin_allowed(allowed_origins: &[String], origin: &str) -> bool {
+    allowed_origins
+        .iter()
+        .any(|allowed| match allowed.strip_suffix('*') {
+            Some(prefix) => origin.starts_with(prefix),
+            None => origin == allowed,
+        })
+}
+
+fn make_cors(config: &config::Cors) -> Cors {
+    let allowed_origins = config.allowed_origins.clone();
+
+    Cors::default()
+        .allowed_origin_fn(move |origin, _| {
+            let allowed = origin
+                .to_str()
+                .map(|origin| is_origin_allowed(&allowed_origins, origin))
+                .unwrap_or(false);
+            if !allowed {
+                warn!("Rejecting request from disallowed origin {:?}", origin);
+            }
+            allowed
+        })
+        .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+        .allowed_headers(config.allowed_headers.iter().map(String::as_str))
+        .max_age(config.max_age.as_secs() as usize)
+}
+
+enum Listener {
+    Plain(TcpListener),
+    Tls(TcpListener, Arc<CertificateResolver>),
+}
+
+fn bind(endpoints: &[config::Endpoint]) -> anyhow::Result<Vec<Listener>> {
+    endpoints
+        .iter()
+        .map(|endpoint| {
+            Ok(match endpoint {
+                config::Endpoint::Plain(address) => Listener::Plain(TcpListener::bind(address)?),
+                config::Endpoint::Tls { address, tls } => {
+                    let resolver = Arc::new(CertificateResolver::new(tls)?);
+                    tokio::spawn(resolver.clone().watch());
+                    Listener::Tls(TcpListener::bind(address)?, resolver)
+                }
+            })
+        })
+        .collect()
+}
+
+/// Runs the HTTP server. When the config changes, the server is gracefully restarted on the same sockets,
+/// so that the clients do not notice anything
 pub async fn run(
     db: Arc<Database>,
     moodle: Arc<Moodle>,
-    config: config::Server,
+    events: Arc<Events>,
+    mut config: watch::Receiver<config::Server>,
 ) -> anyhow::Result<()> {
-    let data = Data { db, moodle };
-
-    let mut http = HttpServer::new(move || {
-        let cors = Cors::permissive();
-
-        App::new()
-            .app_data(web::Data::new(data.clone()))
-            .wrap(TracingLogger::default())
-            .wrap(cors)
-            .service(extend_session)
-    });
-    for endpoint in config.endpoints {
-        http = http.bind(endpoint)?;
+    let listeners = bind(&config.borrow().endpoints)?;
+
+    loop {
+        let server_config = Arc::new(config.borrow_and_update().clone());
+        let cors_config = server_config.cors.clone();
+        let (stop_streams, stopping) = watch::channel(false);
+        let data = Data {
+            db: db.clone(),
+            moodle: moodle.clone(),
+            events: events.clone(),
+            config: server_config,
+            stopping,
+        };
+
+        let mut http = HttpServer::new(move || {
+            let cors = make_cors(&cors_config);
+
+            App::new()
+                .app_data(web::Data::new(data.clone()))
+                .wrap(TracingLogger::default())
+                .wrap(cors)
+                .configure(api::configure)
+                .service(legacy_extend_session)
+        });
+        for listener in &listeners {
+            http = match listener {
+                Listener::Plain(listener) => http.listen(listener.try_clone()?)?,
+                Listener::Tls(listener, resolver) => {
+                    http.listen_rustls(listener.try_clone()?, resolver.server_config())?
+                }
+            };
+        }
+        let mut server = http.run();
+        let handle = server.handle();
+
+        select! {
+            r = &mut server => {
+                r?;
+                return Ok(());
+            }
+            r = config.changed() => {
+                if r.is_err() {
+                    // nobody is going to reload the config anymore
+                    server.await?;
+                    return Ok(());
+                }
+
+                info!("Server config changed, restarting the server");
+                // event streams never finish on their own, the graceful stop would wait for them until it times out
+                let _ = stop_streams.send(true);
+                handle.stop(true).await;
+                server.await?;
+            }
+        }
     }
-    http.run().await?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+    fn allowed_origins() -> Vec<String> {
+        vec![
+            "moz-extension://*".to_string(),
+            "chrome-extension://jlkihkgdajcdpbnkdlamhiggdggnjmil".to_string(),
+        ]
+    }
+
+    #[test]
+    fn allows_exactly_matching_origins() {
+        assert!(is_origin_allowed(
+            &allowed_origins(),
+            "chrome-extension://jlkihkgdajcdpbnkdlamhiggdggnjmil"
+        ));
+        assert!(!is_origin_allowed(
+            &allowed_origins(),
+            "chrome-extension://jlkihkgdajcdpbnkdlamhiggdggnjmilx"
+        ));
+    }
+
+    #[test]
+    fn allows_origins_matching_a_wildcard() {
+        assert!(is_origin_allowed(
+            &allowed_origins(),
+            "moz-extension://0b4e7c5a-3f1d-4c2e-9a8b-6d5f4e3c2b1a"
+        ));
+    }
+
+    #[test]
+    fn rejects_other_origins() {
+        for origin in [
+            "https://evil.example.com",
+            "chrome-extension://someotherextension",
+            "moz-extension:/",
+            "",
+        ] {
+            assert!(!is_origin_allowed(&allowed_origins(), origin), "{}", origin);
+        }
+        assert!(!is_origin_allowed(&[], "moz-extension://anything"));
+    }
 }
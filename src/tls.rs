@@ -0,0 +1,232 @@
+use crate::config;
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::interval;
+use tracing::{error, info, instrument};
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Signature schemes of the supported key types, along with how the certificate verifies them
+static KEY_CHECK_SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 4] = [
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Makes sure the key belongs to the certificate by checking a signature made with it
+fn check_key_matches(certified_key: &CertifiedKey) -> Result<()> {
+    let schemes = KEY_CHECK_SCHEMES
+        .iter()
+        .map(|(scheme, _)| *scheme)
+        .collect::<Vec<_>>();
+    let signer = certified_key
+        .key
+        .choose_scheme(&schemes)
+        .ok_or_else(|| anyhow!("Unsupported private key type"))?;
+    let (_, algorithm) = KEY_CHECK_SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .expect("The signer uses one of the offered schemes");
+
+    let message = b"moodle-session-ext key check";
+    let signature = signer
+        .sign(message)
+        .map_err(|e| anyhow!("Signing with the key: {}", e))?;
+    webpki::EndEntityCert::try_from(certified_key.cert[0].0.as_slice())
+        .map_err(|e| anyhow!("Parsing the certificate: {}", e))?
+        .verify_signature(algorithm, message, &signature)
+        .map_err(|_| anyhow!("The key does not match the certificate"))
+}
+
+fn load_certified_key(certificate: &Utf8Path, key: &Utf8Path) -> Result<CertifiedKey> {
+    let certificate_chain = std::fs::File::open(certificate)
+        .with_context(|| format!("Opening certificate file {}", certificate))?;
+    let certificate_chain = rustls_pemfile::certs(&mut BufReader::new(certificate_chain))
+        .with_context(|| format!("Parsing certificate file {}", certificate))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certificate_chain.is_empty() {
+        return Err(anyhow!("No certificates found in {}", certificate));
+    }
+
+    let key_file = std::fs::File::open(key).with_context(|| format!("Opening key file {}", key))?;
+    let private_key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+        .with_context(|| format!("Parsing key file {}", key))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {}", key))?;
+
+    let signing_key = rustls::sign::any_supported_type(&private_key)
+        .map_err(|_| anyhow!("Unsupported private key type in {}", key))?;
+
+    let certified_key = CertifiedKey::new(certificate_chain, signing_key);
+    check_key_matches(&certified_key)
+        .with_context(|| format!("Checking key {} against certificate {}", key, certificate))?;
+
+    Ok(certified_key)
+}
+
+fn modification_time(path: &Utf8Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Serves the certificate currently loaded from disk, allowing it to be swapped without re-creating the listener
+pub struct CertificateResolver {
+    certificate_path: Utf8PathBuf,
+    key_path: Utf8PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateResolver {
+    pub fn new(config: &config::Tls) -> Result<Self> {
+        let key = load_certified_key(&config.certificate, &config.key)?;
+
+        Ok(Self {
+            certificate_path: config.certificate.clone(),
+            key_path: config.key.clone(),
+            current: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    #[instrument(skip(self), fields(certificate = %self.certificate_path))]
+    fn reload(&self) {
+        match load_certified_key(&self.certificate_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                info!("Reloaded TLS certificate");
+            }
+            // keep serving the old certificate, it might still be valid. A mismatched pair is often caught
+            // halfway through replacing the files, and is picked up once the other one changes too
+            Err(e) => error!("Failed to reload TLS certificate: {:?}", e),
+        }
+    }
+
+    /// Polls the certificate and key files and reloads them when either of them changes
+    pub async fn watch(self: Arc<Self>) {
+        let mut last_modified = (
+            modification_time(&self.certificate_path),
+            modification_time(&self.key_path),
+        );
+        let mut interval = interval(RELOAD_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let modified = (
+                modification_time(&self.certificate_path),
+                modification_time(&self.key_path),
+            );
+            if modified != last_modified {
+                last_modified = modified;
+                self.reload();
+            }
+        }
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn fixture(name: &str) -> Utf8PathBuf {
+        Utf8PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/tls")
+            .join(name)
+    }
+
+    fn certificate(name: &str) -> Certificate {
+        load_certified_key(
+            &fixture(&format!("{}.crt", name)),
+            &fixture(&format!("{}.key", name)),
+        )
+        .unwrap()
+        .cert
+        .remove(0)
+    }
+
+    /// A resolver serving a copy of the `first` pair, which can then be replaced
+    fn resolver(dir: &TempDir) -> CertificateResolver {
+        let config = config::Tls {
+            certificate: Utf8PathBuf::try_from(dir.path().join("server.crt")).unwrap(),
+            key: Utf8PathBuf::try_from(dir.path().join("server.key")).unwrap(),
+        };
+        std::fs::copy(fixture("first.crt"), &config.certificate).unwrap();
+        std::fs::copy(fixture("first.key"), &config.key).unwrap();
+
+        CertificateResolver::new(&config).unwrap()
+    }
+
+    fn served(resolver: &CertificateResolver) -> Certificate {
+        resolver.current.read().unwrap().cert[0].clone()
+    }
+
+    #[test]
+    fn rejects_a_key_of_another_certificate() {
+        let error = match load_certified_key(&fixture("first.crt"), &fixture("second.key")) {
+            Ok(_) => panic!("A mismatched pair was loaded"),
+            Err(e) => e,
+        };
+
+        assert!(format!("{:#}", error).contains("The key does not match the certificate"));
+    }
+
+    #[test]
+    fn reloads_a_replaced_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver = resolver(&dir);
+        assert_eq!(served(&resolver), certificate("first"));
+
+        std::fs::copy(fixture("second.crt"), &resolver.certificate_path).unwrap();
+        std::fs::copy(fixture("second.key"), &resolver.key_path).unwrap();
+        resolver.reload();
+
+        assert_eq!(served(&resolver), certificate("second"));
+    }
+
+    #[test]
+    fn keeps_the_old_pair_if_the_new_one_does_not_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver = resolver(&dir);
+
+        std::fs::copy(fixture("second.crt"), &resolver.certificate_path).unwrap();
+        resolver.reload();
+
+        assert_eq!(served(&resolver), certificate("first"));
+    }
+}
@@ -1,34 +1,180 @@
-use crate::model::{Token, TokenId, UpdateQueueItem, UpdateQueueKey};
-use crate::moodle::SessionUpdateResult;
+use crate::events::{Event, Events, RevokeReason};
+use crate::keepalive::{KeepAlive, WebServicePing};
+use crate::model::{Credential, CredentialKind, Token, TokenId, UpdateQueueItem, UpdateQueueKey};
+use crate::moodle::{is_timed_out, SessionUpdateResult};
+use crate::notify::Notifier;
+use crate::schedule::{self, SessionTimeoutEstimator};
 use crate::{config, Database, Moodle};
 use anyhow::Result;
 use std::ops::Add;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::select;
-use tokio::time::sleep;
-use tracing::{debug, info, instrument, warn};
+use tokio::sync::watch;
+use tokio::time::{sleep, Instant};
+use tracing::{debug, info, instrument, warn, Span};
 
-#[instrument(skip_all, fields(token_id = ?token_id))]
-async fn update_one(db: &Database, moodle: &Moodle, token_id: TokenId, token: Token) -> Result<()> {
+/// How often to check whether the update queue has a cluster of due tokens
+const SMOOTHING_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait before retrying a token moodle timed out on, so that the other tokens get their turn
+const TIMEOUT_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Keeps the credential alive with the strategy for its kind, storing the cookies moodle changed along the way
+async fn keep_alive(
+    db: &Database,
+    moodle: &Moodle,
+    events: &Events,
+    config: &config::Updater,
+    browser_keep_alive: &dyn KeepAlive,
+    token_id: TokenId,
+    token: &Token,
+) -> Result<SessionUpdateResult> {
+    let mut credential = db.credential(token)?;
+    let sent_credential = credential.clone();
+
+    let result = match token.credential {
+        CredentialKind::BrowserSession => {
+            moodle
+                .with_timeout(browser_keep_alive.keep_alive(moodle, &mut credential))
+                .await
+        }
+        CredentialKind::WebServiceToken => {
+            let ping = WebServicePing {
+                interval: config.web_service_interval,
+            };
+            moodle
+                .with_timeout(ping.keep_alive(moodle, &mut credential))
+                .await
+        }
+    };
+
+    if let Credential::BrowserSession { cookies, .. } = &credential {
+        if credential != sent_credential {
+            debug!("Moodle updated the cookies, storing them");
+            if let Some(updated) = db.update_cookies(token_id, cookies)? {
+                if updated.moodle_session != token.moodle_session {
+                    events.emit(Event::session_rotated(token_id, &updated));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Records a refresh that started after the token's deadline, when the session may have expired already.
+/// Tokens that were never refreshed don't have a deadline yet
+fn check_deadline(
+    db: &Database,
+    moodle: &Moodle,
+    events: &Events,
+    token_id: TokenId,
+    token: &Token,
+) -> Result<()> {
+    if token.deadline == SystemTime::UNIX_EPOCH {
+        return Ok(());
+    }
+    let late_by = match db.clock().now().duration_since(token.deadline) {
+        Ok(late_by) => late_by,
+        Err(_) => return Ok(()),
+    };
+
+    let (token, missed_deadlines) = db.record_missed_deadline(token_id)?;
+    Span::current().record("late_by_ms", late_by.as_millis() as u64);
+    warn!(
+        instance = %moodle.base_url(),
+        missed_deadlines,
+        "Refreshing {:?} after its deadline, the session may have expired",
+        late_by
+    );
+
+    if let Some(token) = token {
+        events.emit(Event::deadline_missed(token_id, &token, late_by));
+    }
+
+    Ok(())
+}
+
+#[instrument(skip_all, fields(token_id = ?token_id, late_by_ms = tracing::field::Empty))]
+#[allow(clippy::too_many_arguments)]
+async fn update_one(
+    db: &Database,
+    moodle: &Moodle,
+    notifier: &Notifier,
+    events: &Events,
+    config: &config::Updater,
+    browser_keep_alive: &dyn KeepAlive,
+    estimator: &mut SessionTimeoutEstimator,
+    token_id: TokenId,
+    token: Token,
+) -> Result<()> {
     info!("Updating session {:?}", token_id);
+    check_deadline(db, moodle, events, token_id, &token)?;
+
+    let result = keep_alive(
+        db,
+        moodle,
+        events,
+        config,
+        browser_keep_alive,
+        token_id,
+        &token,
+    )
+    .await;
 
-    match moodle
-        .update_session(&token.moodle_session, &token.csrf_session)
-        .await
-    {
+    match result {
         Ok(v) => match v {
             SessionUpdateResult::Ok { time_left } => {
-                db.update_token(token_id, time_left)?;
+                let refresh_in = match token.credential {
+                    CredentialKind::BrowserSession => {
+                        estimator.observe(time_left);
+                        schedule::refresh_in(config, estimator, time_left)
+                    }
+                    CredentialKind::WebServiceToken => schedule::jittered(config, time_left),
+                };
+                debug!(
+                    "Session has {:?} left, refreshing in {:?}",
+                    time_left, refresh_in
+                );
+
+                if let Some(token) = db.update_token(token_id, time_left, refresh_in)? {
+                    events.emit(Event::refreshed(token_id, &token));
+                }
             }
             SessionUpdateResult::SessionDead => {
                 info!("Session died, removing from db");
 
                 db.remove_token(token_id)?;
+                notifier.session_died(&token);
+                events.emit(Event::died(token_id, &token));
             }
         },
         Err(e) => {
-            warn!("Session update failed: {:?}", e);
+            let timed_out = is_timed_out(&e);
+            if timed_out {
+                warn!("Session update timed out: {:#}", e);
+            } else {
+                warn!("Session update failed: {:?}", e);
+            }
+
+            if let Some(token) = db.record_token_failure(token_id, format!("{:#}", e), timed_out)? {
+                events.emit(Event::refresh_failed(token_id, &token, timed_out));
+
+                if timed_out {
+                    // don't retry past the deadline, it's the last chance to keep the session alive. Tokens that
+                    // were never refreshed don't have a deadline yet, and overdue ones have nothing left to wait for
+                    let now = db.clock().now();
+                    let retry_at = match token.deadline {
+                        deadline if deadline != SystemTime::UNIX_EPOCH && deadline > now => {
+                            (now + TIMEOUT_RETRY_DELAY).min(deadline)
+                        }
+                        _ => now + TIMEOUT_RETRY_DELAY,
+                    };
+                    debug!("Retrying at {:?}", retry_at);
+                    db.retry_token_at(token_id, retry_at)?;
+                } else {
+                    notifier.refresh_failed(&token);
+                }
+            }
         }
     }
 
@@ -38,32 +184,67 @@ async fn update_one(db: &Database, moodle: &Moodle, token_id: TokenId, token: To
 pub async fn update_loop(
     db: Arc<Database>,
     moodle: Arc<Moodle>,
+    notifier: Notifier,
+    events: Arc<Events>,
+    browser_keep_alive: Box<dyn KeepAlive>,
     mut watch: kv::Watch<UpdateQueueKey, UpdateQueueItem>,
-    config: config::Updater,
+    mut config: watch::Receiver<config::Updater>,
 ) -> Result<()> {
+    let mut estimator = SessionTimeoutEstimator::default();
+    let mut last_smoothing: Option<Instant> = None;
+
     loop {
-        debug!("Tracking {} tokens", db.get_token_count()?);
-        let token = db.get_most_urgent_token()?;
+        let current_config = config.borrow_and_update().clone();
+
+        debug!(
+            "Tracking {} tokens, {} missed deadlines so far",
+            db.get_token_count()?,
+            db.get_stats()?.missed_deadlines
+        );
+        let now = db.clock().now();
+
+        if last_smoothing.is_none_or(|t| t.elapsed() >= SMOOTHING_INTERVAL) {
+            let smoothing = &current_config.smoothing;
+            db.smooth_schedule(now, smoothing.cluster_size, smoothing.margin)?;
+            last_smoothing = Some(Instant::now());
+        }
 
-        let now = SystemTime::now();
+        let token = db.get_most_urgent_token()?;
 
-        let deadline = token
+        let next_refresh = token
             .as_ref()
-            .map(|(_, t)| t.deadline)
+            .map(|(_, t)| t.next_refresh)
             .unwrap_or_else(|| now.add(Duration::from_secs(1000000)));
 
-        if deadline < now + config.gap {
+        if next_refresh <= now {
             let (token_id, token) = token.unwrap();
-            update_one(&db, &moodle, token_id, token).await?;
+            if token.extend_until.is_some_and(|until| until <= now) {
+                info!(
+                    "{:?} reached its requested expiry, removing from db",
+                    token_id
+                );
+                db.remove_token(token_id)?;
+                events.emit(Event::revoked(token_id, &token, RevokeReason::Expired));
+                continue;
+            }
+
+            update_one(
+                &db,
+                &moodle,
+                &notifier,
+                &events,
+                &current_config,
+                browser_keep_alive.as_ref(),
+                &mut estimator,
+                token_id,
+                token,
+            )
+            .await?;
         } else {
             info!("Nothing to update it seems")
         }
 
-        let timeout = if deadline < now + config.gap {
-            Duration::ZERO
-        } else {
-            deadline.duration_since(now + config.gap).unwrap()
-        };
+        let timeout = next_refresh.duration_since(now).unwrap_or(Duration::ZERO);
 
         debug!("Setting a timer for {:?}", timeout);
 
@@ -74,6 +255,9 @@ pub async fn update_loop(
             _ = &mut watch => {
                 debug!("Db update spotted, looping");
             },
+            _ = config.changed() => {
+                debug!("Config reloaded, looping");
+            },
         }
 
         // flush all the updates
@@ -81,3 +265,331 @@ pub async fn update_loop(
         while select! { biased; _ = &mut watch => true, _ = &mut ready => false } {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, TokioClock};
+    use crate::cookies::CookieJar;
+    use crate::events::Events;
+    use crate::model::{Credential, Email, RefreshResult};
+    use crate::notify;
+    use crate::webhook::Webhooks;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    /// The times left a session answers its refreshes with, in order
+    type Answers = Vec<(String, Vec<Duration>)>;
+
+    /// Answers each refresh of a session with the next of its scripted times left, recording when it was asked.
+    /// Once a session runs out of answers, its refreshes stall until they time out
+    #[derive(Clone)]
+    struct Scripted {
+        clock: Arc<TokioClock>,
+        answers: Arc<Mutex<Answers>>,
+        calls: Arc<Mutex<Vec<(String, SystemTime)>>>,
+    }
+
+    #[async_trait]
+    impl KeepAlive for Scripted {
+        async fn keep_alive(
+            &self,
+            _moodle: &Moodle,
+            credential: &mut Credential,
+        ) -> Result<SessionUpdateResult> {
+            let session = credential.secret().to_string();
+            self.calls
+                .lock()
+                .unwrap()
+                .push((session.clone(), self.clock.now()));
+
+            let time_left = self
+                .answers
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .find(|(s, _)| *s == session)
+                .and_then(|(_, answers)| (!answers.is_empty()).then(|| answers.remove(0)));
+            match time_left {
+                Some(time_left) => Ok(SessionUpdateResult::Ok { time_left }),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    struct Harness {
+        _dir: TempDir,
+        start: SystemTime,
+        db: Arc<Database>,
+        keep_alive: Scripted,
+        _config: watch::Sender<config::Updater>,
+        updater: tokio::task::JoinHandle<Result<()>>,
+    }
+
+    impl Harness {
+        /// Starts the update loop on a fixed gap schedule, with moodle operations timing out after a minute
+        async fn start(gap: Duration, sessions: &[(&str, &[Duration])]) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            let clock = Arc::new(TokioClock::new(start));
+            let db = Arc::new(
+                Database::new(
+                    config::Database {
+                        path: dir.path().join("db").try_into().unwrap(),
+                        encryption_key: None,
+                    },
+                    clock.clone(),
+                )
+                .unwrap(),
+            );
+            let moodle = Arc::new(
+                Moodle::new(
+                    serde_yaml::from_str(
+                        r#"
+base_url: "https://moodle.example.edu/"
+rpm: 6000
+max_burst: 100
+user_agent: "moodle-session-ext tests"
+http:
+  timeouts:
+    operation: "1m"
+"#,
+                    )
+                    .unwrap(),
+                )
+                .unwrap(),
+            );
+            let (notifier, _) = notify::new(None, db.clone()).unwrap();
+            let events = Arc::new(Events::new(Webhooks::new(None, db.clone())));
+
+            let keep_alive = Scripted {
+                clock,
+                answers: Arc::new(Mutex::new(
+                    sessions
+                        .iter()
+                        .map(|(s, answers)| (s.to_string(), answers.to_vec()))
+                        .collect(),
+                )),
+                calls: Arc::new(Mutex::new(Vec::new())),
+            };
+            for (session, _) in sessions {
+                db.add_token(
+                    &Email("student@example.edu".to_string()),
+                    &Credential::BrowserSession {
+                        moodle_session: session.to_string(),
+                        csrf_session: "sesskey".to_string(),
+                        cookies: CookieJar::new(session),
+                    },
+                    None,
+                    &config::UserLimit {
+                        max_sessions: 10,
+                        when_reached: config::WhenLimitReached::Reject,
+                    },
+                )
+                .unwrap();
+            }
+
+            let (config_sender, config) = watch::channel(config::Updater {
+                schedule: config::Schedule::FixedGap { gap },
+                jitter: 0.0,
+                smoothing: config::Smoothing {
+                    cluster_size: 10,
+                    margin: MINUTE,
+                },
+                web_service_interval: Duration::from_secs(6 * 60 * 60),
+            });
+            let updater = tokio::spawn(update_loop(
+                db.clone(),
+                moodle,
+                notifier,
+                events,
+                Box::new(keep_alive.clone()),
+                db.subscribe_queue_updates().unwrap(),
+                config,
+            ));
+
+            Self {
+                _dir: dir,
+                start,
+                db,
+                keep_alive,
+                _config: config_sender,
+                updater,
+            }
+        }
+
+        fn answer(&self, session: &str, time_left: Duration) {
+            let mut answers = self.keep_alive.answers.lock().unwrap();
+            let (_, answers) = answers.iter_mut().find(|(s, _)| s == session).unwrap();
+            answers.push(time_left);
+        }
+
+        /// Which session was refreshed how long after the start
+        fn calls(&self) -> Vec<(String, Duration)> {
+            self.keep_alive
+                .calls
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(session, at)| (session.clone(), at.duration_since(self.start).unwrap()))
+                .collect()
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            self.updater.abort();
+        }
+    }
+
+    fn calls(expected: &[(&str, Duration)]) -> Vec<(String, Duration)> {
+        expected
+            .iter()
+            .map(|(session, after)| (session.to_string(), *after))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refreshes_the_gap_before_the_deadline() {
+        let harness = Harness::start(5 * MINUTE, &[("a", &[30 * MINUTE; 3])]).await;
+
+        sleep(60 * MINUTE).await;
+
+        assert_eq!(
+            harness.calls(),
+            calls(&[
+                ("a", Duration::ZERO),
+                ("a", 25 * MINUTE),
+                ("a", 50 * MINUTE)
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refreshes_right_away_when_less_than_the_gap_is_left() {
+        let harness = Harness::start(10 * MINUTE, &[("a", &[5 * MINUTE, 30 * MINUTE])]).await;
+
+        sleep(MINUTE).await;
+
+        assert_eq!(
+            harness.calls(),
+            calls(&[("a", Duration::ZERO), ("a", Duration::ZERO)])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refreshes_sessions_in_the_order_they_are_due() {
+        let harness = Harness::start(
+            MINUTE,
+            &[
+                ("a", &[30 * MINUTE; 2]),
+                ("b", &[10 * MINUTE; 4]),
+                ("c", &[20 * MINUTE; 2]),
+            ],
+        )
+        .await;
+
+        sleep(30 * MINUTE).await;
+
+        assert_eq!(
+            harness.calls(),
+            calls(&[
+                ("a", Duration::ZERO),
+                ("b", Duration::ZERO),
+                ("c", Duration::ZERO),
+                ("b", 9 * MINUTE),
+                ("b", 18 * MINUTE),
+                ("c", 19 * MINUTE),
+                ("b", 27 * MINUTE),
+                ("a", 29 * MINUTE),
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_a_timed_out_refresh_after_a_delay() {
+        // the refresh due at 25 minutes times out a minute later, and is retried after another one
+        let harness = Harness::start(5 * MINUTE, &[("a", &[30 * MINUTE])]).await;
+
+        sleep(26 * MINUTE + MINUTE / 2).await;
+        let (_, token) = harness.db.get_most_urgent_token().unwrap().unwrap();
+        assert!(matches!(
+            token.recent_outcomes.back().unwrap().result,
+            RefreshResult::TimedOut { .. }
+        ));
+        assert_eq!(token.consecutive_failures, 0);
+        assert_eq!(token.next_refresh, harness.start + 27 * MINUTE);
+
+        harness.answer("a", 30 * MINUTE);
+        sleep(10 * MINUTE).await;
+
+        assert_eq!(
+            harness.calls(),
+            calls(&[
+                ("a", Duration::ZERO),
+                ("a", 25 * MINUTE),
+                ("a", 27 * MINUTE)
+            ])
+        );
+        let (_, token) = harness.db.get_most_urgent_token().unwrap().unwrap();
+        assert_eq!(token.deadline, harness.start + 57 * MINUTE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_new_session_timing_out_does_not_hold_up_the_others() {
+        // "a" was never refreshed, so it has no deadline to retry by
+        let harness = Harness::start(5 * MINUTE, &[("a", &[]), ("b", &[30 * MINUTE])]).await;
+
+        sleep(2 * MINUTE + MINUTE / 2).await;
+
+        assert_eq!(
+            harness.calls(),
+            calls(&[("a", Duration::ZERO), ("b", MINUTE), ("a", 2 * MINUTE)])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_a_timed_out_refresh_no_later_than_the_deadline() {
+        // the refresh due at 28.5 minutes times out half a minute before the deadline
+        let harness = Harness::start(MINUTE + MINUTE / 2, &[("a", &[30 * MINUTE])]).await;
+
+        sleep(29 * MINUTE + MINUTE / 2).await;
+        harness.answer("a", 30 * MINUTE);
+        sleep(10 * MINUTE).await;
+
+        assert_eq!(
+            harness.calls(),
+            calls(&[
+                ("a", Duration::ZERO),
+                ("a", 28 * MINUTE + MINUTE / 2),
+                ("a", 30 * MINUTE)
+            ])
+        );
+        // the retry started right at the deadline
+        let (_, token) = harness.db.get_most_urgent_token().unwrap().unwrap();
+        assert_eq!(token.missed_deadlines, 1);
+        assert_eq!(harness.db.get_stats().unwrap().missed_deadlines, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_a_refresh_timed_out_at_the_deadline_after_a_delay() {
+        // the refresh due at 29 minutes times out right at the deadline, which can't hold the retry back anymore
+        let harness = Harness::start(MINUTE, &[("a", &[30 * MINUTE])]).await;
+
+        sleep(30 * MINUTE + MINUTE / 2).await;
+        harness.answer("a", 30 * MINUTE);
+        sleep(10 * MINUTE).await;
+
+        assert_eq!(
+            harness.calls(),
+            calls(&[
+                ("a", Duration::ZERO),
+                ("a", 29 * MINUTE),
+                ("a", 31 * MINUTE)
+            ])
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use crate::events::Payload;
+use crate::model::{unix_millis, WebhookDelivery, WebhookDeliveryKey};
+use crate::{config, Database};
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::sleep;
+use tracing::{debug, error, info, instrument, warn};
+
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+
+/// Queues webhook deliveries for the configured endpoints. Does nothing if webhooks are not configured
+pub struct Webhooks {
+    db: Arc<Database>,
+    endpoints: Vec<config::WebhookEndpoint>,
+}
+
+impl Webhooks {
+    pub fn new(config: Option<&config::Webhooks>, db: Arc<Database>) -> Self {
+        Self {
+            db,
+            endpoints: config.map(|c| c.endpoints.clone()).unwrap_or_default(),
+        }
+    }
+
+    /// Persists a delivery of the event for every interested endpoint. Failures are only logged,
+    /// as they should not affect the session handling
+    pub fn deliver(&self, payload: &Payload) {
+        let endpoints = self
+            .endpoints
+            .iter()
+            .filter(|e| {
+                e.events
+                    .as_ref()
+                    .is_none_or(|ev| ev.contains(&payload.event.kind()))
+            })
+            .collect::<Vec<_>>();
+        if endpoints.is_empty() {
+            return;
+        }
+
+        let now = self.db.clock().now();
+        let body = match serde_json::to_string(payload) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Failed to serialize webhook payload {:?}: {:?}", payload, e);
+                return;
+            }
+        };
+
+        for endpoint in endpoints {
+            let delivery = WebhookDelivery {
+                url: endpoint.url.to_string(),
+                payload: body.clone(),
+                attempts: 0,
+                created: now,
+                last_error: None,
+            };
+            if let Err(e) = self.db.add_webhook_delivery(delivery) {
+                error!("Failed to queue webhook delivery: {:?}", e);
+            }
+        }
+    }
+}
+
+fn sign(secret: &str, timestamp: u64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// How long to wait before retrying a delivery that failed `attempts` times
+fn backoff(config: &config::Webhooks, attempts: u32) -> Duration {
+    config
+        .initial_backoff
+        .saturating_mul(2u32.saturating_pow(attempts - 1))
+        .min(config.max_backoff)
+}
+
+/// Sends the persisted webhook deliveries, retrying failed ones with exponential backoff
+pub struct Dispatcher {
+    db: Arc<Database>,
+    config: Option<config::Webhooks>,
+    reqwest: reqwest::Client,
+}
+
+impl Dispatcher {
+    pub fn new(config: Option<config::Webhooks>, db: Arc<Database>) -> Result<Self> {
+        let mut builder = reqwest::ClientBuilder::new();
+        if let Some(config) = &config {
+            builder = builder.timeout(config.timeout);
+        }
+
+        Ok(Self {
+            db,
+            config,
+            reqwest: builder.build()?,
+        })
+    }
+
+    async fn send(
+        &self,
+        endpoint: &config::WebhookEndpoint,
+        key: WebhookDeliveryKey,
+        delivery: &WebhookDelivery,
+    ) -> Result<()> {
+        let timestamp = unix_millis(self.db.clock().now()) / 1000;
+
+        let resp = self
+            .reqwest
+            .post(endpoint.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(
+                SIGNATURE_HEADER,
+                sign(endpoint.secret.expose(), timestamp, &delivery.payload),
+            )
+            .header(DELIVERY_HEADER, key.delivery_id())
+            .body(delivery.payload.clone())
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(anyhow!("Endpoint responded with status {}", resp.status()));
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, config, delivery), fields(url = %delivery.url))]
+    async fn attempt(
+        &self,
+        config: &config::Webhooks,
+        key: WebhookDeliveryKey,
+        mut delivery: WebhookDelivery,
+    ) -> Result<()> {
+        let endpoint = match config
+            .endpoints
+            .iter()
+            .find(|e| e.url.as_str() == delivery.url)
+        {
+            Some(e) => e,
+            None => {
+                warn!("Webhook endpoint is not configured anymore, dropping the delivery");
+                return self.db.remove_webhook_delivery(key);
+            }
+        };
+
+        match self.send(endpoint, key, &delivery).await {
+            Ok(()) => {
+                debug!("Webhook delivered");
+                self.db.remove_webhook_delivery(key)
+            }
+            Err(e) => {
+                delivery.attempts += 1;
+                delivery.last_error = Some(format!("{:#}", e));
+
+                if delivery.attempts >= config.max_attempts {
+                    error!(
+                        "Webhook delivery failed {} times, giving up: {:?}",
+                        delivery.attempts, e
+                    );
+                    return self.db.remove_webhook_delivery(key);
+                }
+
+                let backoff = backoff(config, delivery.attempts);
+                warn!(
+                    "Webhook delivery failed (attempt {}), retrying in {:?}: {:?}",
+                    delivery.attempts, backoff, e
+                );
+                self.db
+                    .reschedule_webhook_delivery(key, &delivery, self.db.clock().now() + backoff)
+            }
+        }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let config = match &self.config {
+            Some(c) => c,
+            None => {
+                info!("Webhooks are not configured");
+                return std::future::pending().await;
+            }
+        };
+
+        let mut watch = self.db.subscribe_webhook_deliveries()?;
+
+        loop {
+            let now = self.db.clock().now();
+
+            let next_attempt = match self.db.next_webhook_delivery()? {
+                Some((key, delivery)) if key.next_attempt() <= now => {
+                    self.attempt(config, key, delivery)
+                        .await
+                        .context("Attempting a webhook delivery")?;
+                    continue;
+                }
+                Some((key, _)) => key.next_attempt(),
+                None => now + Duration::from_secs(1000000),
+            };
+
+            let timeout = next_attempt.duration_since(now).unwrap_or(Duration::ZERO);
+
+            select! {
+                _ = sleep(timeout) => {},
+                _ = &mut watch => {},
+            }
+
+            // flush all the updates
+            let mut ready = std::future::ready(());
+            while select! { biased; _ = &mut watch => true, _ = &mut ready => false } {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, SystemClock};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    const PAYLOAD: &str = r#"{"event":"token_registered"}"#;
+
+    /// A clock that never moves, so that the backoff can be told exactly
+    struct Frozen(SystemTime);
+
+    impl Clock for Frozen {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+    }
+
+    /// Headers (with lowercase names) and body of a request the endpoint received
+    type Received = Arc<Mutex<Vec<(HashMap<String, String>, String)>>>;
+
+    /// Starts an endpoint answering every request with `status`
+    fn endpoint(status: u16) -> (String, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = Received::default();
+
+        let requests = received.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut headers = HashMap::new();
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((name, value)) => {
+                            headers.insert(name.to_lowercase(), value.to_string())
+                        }
+                        None => break,
+                    };
+                }
+                let mut body = vec![0; headers["content-length"].parse().unwrap()];
+                stream.read_exact(&mut body).unwrap();
+                requests
+                    .lock()
+                    .unwrap()
+                    .push((headers, String::from_utf8(body).unwrap()));
+
+                write!(
+                    stream.get_mut(),
+                    "HTTP/1.1 {} Whatever\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+
+        (url, received)
+    }
+
+    fn config(url: &str) -> config::Webhooks {
+        serde_yaml::from_str(&format!(
+            r#"
+endpoints:
+  - url: "{}"
+    secret: "whsec_test"
+max_attempts: 3
+initial_backoff: "10s"
+max_backoff: "1m"
+timeout: "10s"
+"#,
+            url
+        ))
+        .unwrap()
+    }
+
+    fn database(dir: &TempDir, clock: Arc<dyn Clock>) -> Arc<Database> {
+        Arc::new(
+            Database::new(
+                config::Database {
+                    path: dir.path().join("db").try_into().unwrap(),
+                    encryption_key: None,
+                },
+                clock,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn delivery(url: &str, created: SystemTime) -> WebhookDelivery {
+        WebhookDelivery {
+            url: url.to_string(),
+            payload: PAYLOAD.to_string(),
+            attempts: 0,
+            created,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_the_payload() {
+        assert_eq!(
+            sign("whsec_test", 1_700_000_000, PAYLOAD),
+            "sha256=1e8e66ae9150ac9bce22b95ad0df9263a8e2f8c34562d9422ffc9f42700176a5"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let config = config("https://bot.example.com/");
+        let backoffs = (1..=5)
+            .map(|attempts| backoff(&config, attempts).as_secs())
+            .collect::<Vec<_>>();
+
+        assert_eq!(backoffs, vec![10, 20, 40, 60, 60]);
+        assert_eq!(backoff(&config, 100), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries_until_the_attempts_run_out() {
+        let (url, received) = endpoint(500);
+        let dir = tempfile::tempdir().unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let db = database(&dir, Arc::new(Frozen(start)));
+        let config = config(&url);
+        let dispatcher = Dispatcher::new(Some(config.clone()), db.clone()).unwrap();
+        db.add_webhook_delivery(delivery(&url, start)).unwrap();
+
+        for (attempts, next_attempt) in [(1, 10), (2, 20)] {
+            let (key, delivery) = db.next_webhook_delivery().unwrap().unwrap();
+            dispatcher.attempt(&config, key, delivery).await.unwrap();
+
+            let (rescheduled, delivery) = db.next_webhook_delivery().unwrap().unwrap();
+            assert_eq!(rescheduled.delivery_id(), key.delivery_id());
+            assert_eq!(
+                rescheduled.next_attempt(),
+                start + Duration::from_secs(next_attempt)
+            );
+            assert_eq!(delivery.attempts, attempts);
+            assert!(delivery.last_error.unwrap().contains("500"));
+        }
+
+        let (key, delivery) = db.next_webhook_delivery().unwrap().unwrap();
+        dispatcher.attempt(&config, key, delivery).await.unwrap();
+
+        assert!(db.next_webhook_delivery().unwrap().is_none());
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn delivers_the_pending_deliveries_after_a_restart() {
+        let (url, received) = endpoint(200);
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = database(&dir, Arc::new(SystemClock));
+            db.add_webhook_delivery(delivery(&url, SystemTime::now()))
+                .unwrap();
+            db.add_webhook_delivery(delivery(&url, SystemTime::now()))
+                .unwrap();
+        }
+
+        let db = database(&dir, Arc::new(SystemClock));
+        let dispatcher = tokio::spawn(
+            Dispatcher::new(Some(config(&url)), db.clone())
+                .unwrap()
+                .run(),
+        );
+        for _ in 0..100 {
+            if db.next_webhook_delivery().unwrap().is_none() {
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        dispatcher.abort();
+
+        assert!(db.next_webhook_delivery().unwrap().is_none());
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        for (headers, body) in received.iter() {
+            assert_eq!(body, PAYLOAD);
+            let timestamp = headers["x-webhook-timestamp"].parse().unwrap();
+            assert_eq!(
+                headers["x-webhook-signature"],
+                sign("whsec_test", timestamp, PAYLOAD)
+            );
+        }
+        assert_ne!(
+            received[0].0["x-webhook-delivery"],
+            received[1].0["x-webhook-delivery"]
+        );
+    }
+}